@@ -0,0 +1,97 @@
+#!/usr/bin/env python3
+"""Fill the HV_SYMBOLS placeholder of the hvisor ELF with its function symbols.
+
+The table is written in place, so no address in the image changes. Run after
+every link, see src/symbols.rs for the layout.
+
+usage: gen_symbols.py <elf> [nm]
+"""
+
+import re
+import struct
+import subprocess
+import sys
+
+SYMBOL_TABLE_SIZE = 0x40000
+SYMBOL_TABLE_MAGIC = 0x59535648
+HEADER = struct.Struct("<II")
+ENTRY = struct.Struct("<QII")
+MAX_NAME_LEN = 128
+
+
+def function_symbols(elf, nm):
+    out = subprocess.run(
+        [nm, "--defined-only", "--demangle", elf],
+        check=True,
+        capture_output=True,
+        text=True,
+    ).stdout
+    symbols = {}
+    for line in out.splitlines():
+        parts = line.split(" ", 2)
+        if len(parts) != 3 or parts[1] not in "tTwW":
+            continue
+        addr = int(parts[0], 16)
+        # drop the legacy mangling hash, `::h0123456789abcdef`
+        name = re.sub(r"::h[0-9a-f]{16}$", "", parts[2])
+        symbols.setdefault(addr, name[:MAX_NAME_LEN])
+    return sorted(symbols.items())
+
+
+def build_table(symbols):
+    while True:
+        names = bytearray()
+        entries = bytearray()
+        for addr, name in symbols:
+            encoded = name.encode()
+            entries += ENTRY.pack(addr, len(names), len(encoded))
+            names += encoded
+        table = HEADER.pack(SYMBOL_TABLE_MAGIC, len(symbols)) + entries + names
+        if len(table) <= SYMBOL_TABLE_SIZE:
+            return table.ljust(SYMBOL_TABLE_SIZE, b"\0"), len(symbols)
+        # keep the lowest addresses, where hvisor's own code is
+        symbols = symbols[: len(symbols) * 9 // 10]
+
+
+def file_offset(elf_data, addr):
+    """File offset of virtual address `addr`, from the section headers of an ELF64."""
+    (shoff,) = struct.unpack_from("<Q", elf_data, 0x28)
+    shentsize, shnum = struct.unpack_from("<HH", elf_data, 0x3A)
+    for i in range(shnum):
+        base = shoff + i * shentsize
+        sh_type, sh_flags, sh_addr, sh_offset, sh_size = struct.unpack_from(
+            "<IQQQQ", elf_data, base + 4
+        )
+        # SHF_ALLOC, and not SHT_NOBITS which has no bytes in the file
+        if sh_flags & 2 and sh_type != 8 and sh_addr <= addr < sh_addr + sh_size:
+            return sh_offset + addr - sh_addr
+    sys.exit("HV_SYMBOLS is not in any section with file contents")
+
+
+def main():
+    if len(sys.argv) < 2:
+        sys.exit(__doc__)
+    elf = sys.argv[1]
+    nm = sys.argv[2] if len(sys.argv) > 2 else "rust-nm"
+
+    symbols = function_symbols(elf, nm)
+    table, count = build_table(symbols)
+    placeholder = [
+        line.split()
+        for line in subprocess.run(
+            [nm, "--defined-only", elf], check=True, capture_output=True, text=True
+        ).stdout.splitlines()
+        if line.endswith(" HV_SYMBOLS")
+    ]
+    if not placeholder:
+        sys.exit("no HV_SYMBOLS in " + elf)
+
+    with open(elf, "r+b") as f:
+        data = f.read()
+        f.seek(file_offset(data, int(placeholder[0][0], 16)))
+        f.write(table)
+    print("gen_symbols: %d of %d symbols" % (count, len(symbols)))
+
+
+if __name__ == "__main__":
+    main()
@@ -1,23 +1,40 @@
 use crate::{
     arch::{mm::new_s2_memory_set, sysreg::write_sysreg},
-    consts::{PAGE_SIZE, PER_CPU_ARRAY_PTR, PER_CPU_SIZE},
+    consts::{MAX_CPU_NUM, PAGE_SIZE, PER_CPU_ARRAY_PTR, PER_CPU_SIZE},
     memory::{
         addr::PHYS_VIRT_OFFSET, mm::PARKING_MEMORY_SET, GuestPhysAddr, HostPhysAddr, MemFlags,
         MemoryRegion, VirtAddr, PARKING_INST_PAGE,
     },
-    percpu::this_cpu_data,
+    percpu::{cpu_num, this_cpu_data},
 };
 use aarch64_cpu::registers::{
-    Readable, Writeable, ELR_EL2, HCR_EL2, MPIDR_EL1, SCTLR_EL1, SPSR_EL2, VTCR_EL2,
+    Readable, Writeable, CNTFRQ_EL0, CNTPCT_EL0, ELR_EL2, HCR_EL2, MPIDR_EL1, SCTLR_EL1, SPSR_EL2,
+    TPIDR_EL2, VTCR_EL2,
 };
+use alloc::vec::Vec;
+use psci::LowestAffinityLevel;
+use spin::Once;
 
 use super::{
+    entry::mark_boot_cpu_started,
     mm::{get_parange, get_parange_bits, is_s2_pt_level3},
     trap::vmreturn,
 };
 
-pub fn cpu_start(cpuid: usize, start_addr: usize, opaque: usize) {
-    psci::cpu_on(cpuid as u64 | 0x80000000, start_addr as _, opaque as _).unwrap_or_else(|err| {
+/// Aff3, Aff2, Aff1 and Aff0 of MPIDR_EL1.
+const MPIDR_AFF_MASK: u64 = 0xff_00ff_ffff;
+
+/// MPIDR_EL1 bit 31 is RES1.
+const MPIDR_RES1: u64 = 1 << 31;
+
+/// MPIDR affinity bits of each logical cpu, the boot cpu is cpu 0.
+static CPU_MPIDRS: Once<Vec<u64>> = Once::new();
+
+/// Start logical cpu `cpuid` at `start_addr`. `arch_entry` gets the logical cpu
+/// id as context instead of `opaque`, as it needs it to find its stack.
+pub fn cpu_start(cpuid: usize, start_addr: usize, _opaque: usize) {
+    mark_boot_cpu_started();
+    psci::cpu_on(cpuid_to_mpidr(cpuid), start_addr as _, cpuid as _).unwrap_or_else(|err| {
         if let psci::error::Error::AlreadyOn = err {
         } else {
             panic!("can't wake up cpu {}", cpuid);
@@ -25,6 +42,73 @@ pub fn cpu_start(cpuid: usize, start_addr: usize, opaque: usize) {
     });
 }
 
+/// The MPIDRs of the cpus PSCI `AFFINITY_INFO` knows about, only used when the
+/// host dtb has no `/cpus`. Aff1 and Aff0 are each assumed contiguous from 0,
+/// under the Aff3 and Aff2 of the boot cpu, which covers the cpus numbered by
+/// Aff0 in clusters of Aff1 as well as the cpus numbered by Aff1 with Aff0 0.
+/// A cluster ends at the first missing Aff0, the probing at the first empty
+/// cluster.
+pub fn firmware_cpu_hw_ids() -> Vec<u64> {
+    let present = |mpidr| psci::affinity_info(mpidr, LowestAffinityLevel::All).is_ok();
+    let base = MPIDR_EL1.get() & MPIDR_AFF_MASK & !0xffff;
+    let mut mpidrs = Vec::new();
+    for aff1 in 0..=0xff {
+        let cluster = base | aff1 << 8;
+        if mpidrs.len() == MAX_CPU_NUM || !present(cluster) {
+            break;
+        }
+        mpidrs.push(cluster);
+        for aff0 in 1..=0xff {
+            if mpidrs.len() == MAX_CPU_NUM || !present(cluster | aff0) {
+                break;
+            }
+            mpidrs.push(cluster | aff0);
+        }
+    }
+    mpidrs
+}
+
+/// Build the logical cpu id <-> MPIDR table from the MPIDRs of the cpus
+/// present. The boot cpu becomes cpu 0, the others keep their order.
+pub fn init_cpu_mpidrs(mut mpidrs: Vec<u64>) {
+    let boot_mpidr = MPIDR_EL1.get() & MPIDR_AFF_MASK;
+    mpidrs.iter_mut().for_each(|mpidr| *mpidr &= MPIDR_AFF_MASK);
+    mpidrs.retain(|&mpidr| mpidr != boot_mpidr);
+    mpidrs.insert(0, boot_mpidr);
+    mpidrs.truncate(cpu_num());
+
+    for (cpuid, mpidr) in mpidrs.iter().enumerate() {
+        debug!("cpu {} mpidr {:#x}", cpuid, mpidr);
+    }
+    CPU_MPIDRS.call_once(|| mpidrs);
+}
+
+pub fn cpuid_to_mpidr(cpuid: usize) -> u64 {
+    CPU_MPIDRS.get().unwrap()[cpuid]
+}
+
+/// The logical cpu id of `mpidr`, only its affinity bits are compared.
+pub fn mpidr_to_cpuid(mpidr: u64) -> Option<usize> {
+    CPU_MPIDRS
+        .get()
+        .unwrap()
+        .iter()
+        .position(|&m| m == mpidr & MPIDR_AFF_MASK)
+}
+
+/// The MPIDR affinity of vCPU `vcpu_id` of a zone. There are 16 vCPUs per Aff1,
+/// so that guests can target any vCPU by an SGI without the GICv3 range selector.
+pub fn vcpu_to_mpidr(vcpu_id: usize) -> u64 {
+    (((vcpu_id / 16) as u64) << 8) | (vcpu_id % 16) as u64
+}
+
+/// The vCPU id of `mpidr`, the inverse of [`vcpu_to_mpidr`].
+pub fn mpidr_to_vcpu(mpidr: u64) -> Option<usize> {
+    let mpidr = mpidr & MPIDR_AFF_MASK;
+    let (aff1, aff0) = ((mpidr >> 8) & 0xff, mpidr & 0xff);
+    (mpidr >> 16 == 0 && aff0 < 16).then_some((aff1 * 16 + aff0) as usize)
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct GeneralRegisters {
@@ -90,11 +174,26 @@ impl ArchCpu {
         );
     }
 
+    /// MPIDR_EL1 as seen by the guest, the vCPU MPIDR if the zone has vCPU ids.
+    fn guest_mpidr(&self) -> u64 {
+        let vcpu_id = this_cpu_data().zone.as_ref().and_then(|zone| {
+            let zone_r = zone.read();
+            zone_r
+                .has_vcpu_ids()
+                .then(|| zone_r.cpu_to_vcpu(self.cpuid))
+                .flatten()
+        });
+        match vcpu_id {
+            Some(vcpu_id) => MPIDR_RES1 | vcpu_to_mpidr(vcpu_id),
+            None => MPIDR_EL1.get(),
+        }
+    }
+
     fn stack_top(&self) -> VirtAddr {
         PER_CPU_ARRAY_PTR as VirtAddr + (self.cpuid + 1) as usize * PER_CPU_SIZE
     }
 
-    fn guest_reg(&self) -> &mut GeneralRegisters {
+    pub fn guest_reg(&self) -> &mut GeneralRegisters {
         unsafe { &mut *((self.stack_top() - 32 * 8) as *mut GeneralRegisters) }
     }
 
@@ -147,6 +246,7 @@ impl ArchCpu {
         assert!(this_cpu_id() == self.cpuid);
         this_cpu_data().activate_gpm();
         self.reset(this_cpu_data().cpu_on_entry, this_cpu_data().dtb_ipa);
+        write_sysreg!(VMPIDR_EL2, self.guest_mpidr());
         self.psci_on = true;
         unsafe {
             vmreturn(self.guest_reg() as *mut _ as usize);
@@ -185,12 +285,18 @@ impl ArchCpu {
     }
 }
 
-pub fn mpidr_to_cpuid(mpidr: u64) -> u64 {
-    mpidr & 0xff00ffffff
+/// The logical id of the current cpu, which `arch_entry` keeps in TPIDR_EL2.
+pub fn this_cpu_id() -> usize {
+    TPIDR_EL2.get() as _
 }
 
-pub fn this_cpu_id() -> usize {
-    mpidr_to_cpuid(MPIDR_EL1.get()) as _
+/// The physical count of the generic timer.
+pub fn read_counter() -> u64 {
+    CNTPCT_EL0.get()
+}
+
+pub fn counter_frequency() -> u64 {
+    CNTFRQ_EL0.get()
 }
 
 pub unsafe fn enable_mmu() {
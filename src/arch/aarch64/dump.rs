@@ -0,0 +1,75 @@
+//! State of the current cpu for the panic report.
+
+use aarch64_cpu::registers::{Readable, ELR_EL2, ESR_EL2, FAR_EL2, SPSR_EL2};
+
+use super::{
+    cpu::{this_cpu_id, ArchCpu},
+    sysreg::read_sysreg,
+};
+use crate::{
+    consts::{core_end, PER_CPU_SIZE},
+    symbols,
+};
+
+/// Frames followed at most, in case the frame records are corrupted.
+const MAX_FRAMES: usize = 32;
+
+/// The registers of the last exception taken to EL2.
+pub fn dump_el2_regs() {
+    println!(
+        "ESR_EL2: {:#018x}  ELR_EL2:  {:#018x}",
+        ESR_EL2.get(),
+        ELR_EL2.get()
+    );
+    println!(
+        "FAR_EL2: {:#018x}  SPSR_EL2: {:#018x}  HPFAR_EL2: {:#018x}",
+        FAR_EL2.get(),
+        SPSR_EL2.get(),
+        read_sysreg!(HPFAR_EL2)
+    );
+}
+
+pub fn dump_guest_regs(cpu: &ArchCpu) {
+    for (i, regs) in cpu.guest_reg().usr.chunks(4).enumerate() {
+        for (j, reg) in regs.iter().enumerate() {
+            print!("x{:<2}: {:#018x}  ", i * 4 + j, reg);
+        }
+        println!();
+    }
+}
+
+/// Walk the frame records from the caller, hvisor is built with frame pointers.
+/// A frame record is the caller's x29 followed by the return address.
+pub fn dump_backtrace() {
+    let mut fp: usize;
+    unsafe { core::arch::asm!("mov {}, x29", out(reg) fp) };
+
+    if !symbols::available() {
+        println!("backtrace (no symbol table, see scripts/gen_symbols.py):");
+    } else {
+        println!("backtrace:");
+    }
+    // only the stack of this cpu, the number of cpus may not be known yet
+    let stack_bottom = core_end() + this_cpu_id() * PER_CPU_SIZE;
+    let stacks = stack_bottom..stack_bottom + PER_CPU_SIZE;
+    for i in 0..MAX_FRAMES {
+        if fp % 8 != 0 || !stacks.contains(&fp) || !stacks.contains(&(fp + 8)) {
+            break;
+        }
+        let (next_fp, lr) = unsafe { (*(fp as *const usize), *((fp + 8) as *const usize)) };
+        if lr == 0 {
+            break;
+        }
+        // the call is the instruction before the return address
+        let pc = lr - 4;
+        match symbols::lookup(pc) {
+            Some((name, offset)) => {
+                println!("  #{:<2} {:#018x} {}+{:#x}", i, pc, name, offset);
+            }
+            None => {
+                println!("  #{:<2} {:#018x} <unknown>", i, pc);
+            }
+        }
+        fp = next_fp;
+    }
+}
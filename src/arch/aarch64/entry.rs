@@ -1,9 +1,24 @@
 use core::arch::global_asm;
 
-use crate::consts::PER_CPU_SIZE;
+use super::mm::dcache_clean_range;
+use crate::{consts::PER_CPU_SIZE, memory::VirtAddr};
 
 global_asm!(include_str!("boot_pt.S"));
 
+/// Nonzero once the boot cpu is up, so that `arch_entry` can tell it from the
+/// other cpus. Kept in .data, as .bss is only cleared by the boot cpu.
+#[link_section = ".data"]
+static mut BOOT_CPU_STARTED: u64 = 0;
+
+/// Called before the other cpus are started. They read the flag with the MMU
+/// off, so it is cleaned to the point of coherency.
+pub fn mark_boot_cpu_started() {
+    unsafe {
+        BOOT_CPU_STARTED = 1;
+        dcache_clean_range(&BOOT_CPU_STARTED as *const _ as VirtAddr, 8);
+    }
+}
+
 #[naked]
 #[no_mangle]
 #[link_section = ".text.entry"]
@@ -11,27 +26,35 @@ pub unsafe extern "C" fn arch_entry() -> i32 {
     unsafe {
         core::arch::asm!(
             "
-            // x0 = dtbaddr
+            // boot cpu: x0 = dtbaddr, other cpus: x0 = cpuid (see cpu_start)
+            adrp x1, {boot_cpu_started}
+            ldr x1, [x1, :lo12:{boot_cpu_started}]
+            cbnz x1, 1f
             mov x18, x0
-            mrs x0, mpidr_el1
-            and x0, x0, #0xff
+            mov x0, 0                 // the boot cpu is cpu 0
+            b 2f
+        1:
+            mov x18, 0
+        2:
+            msr tpidr_el2, x0         // tpidr_el2 = cpuid
             adrp x2, __core_end          // x2 = &__core_end
             mov x3, {per_cpu_size}      // x3 = per_cpu_size
             madd x4, x0, x3, x3       // x4 = cpuid * per_cpu_size
             add x5, x2, x4
             mov sp, x5                // sp = &__core_end + (cpuid + 1) * per_cpu_size
 
-            cmp x0, 0
-            b.ne 1f
+            cbnz x1, 3f
             bl {clear_bss}
             bl boot_pt_init
-        1:
+        3:
             bl enable_boot_pt
+            mrs x0, tpidr_el2
             mov x1, x18
             mov x18, 0
             bl {rust_main}            // x0 = cpuid, x1 = dtbaddr
             ",
             options(noreturn),
+            boot_cpu_started = sym BOOT_CPU_STARTED,
             per_cpu_size=const PER_CPU_SIZE,
             rust_main = sym crate::rust_main,
             clear_bss = sym crate::clear_bss,
@@ -1,13 +1,17 @@
-use super::sysreg::write_sysreg;
+use super::{cpu::cpuid_to_mpidr, sysreg::write_sysreg};
 
 pub fn arch_send_event(cpu_id: u64, sgi_num: u64) {
-    let aff3: u64 = 0 << 48;
-    let aff2: u64 = 0 << 32;
-    let aff1: u64 = 0 << 16;
+    let mpidr = cpuid_to_mpidr(cpu_id as _);
+    let aff0 = mpidr & 0xff;
+    let aff3: u64 = ((mpidr >> 32) & 0xff) << 48;
+    let aff2: u64 = ((mpidr >> 16) & 0xff) << 32;
+    let aff1: u64 = ((mpidr >> 8) & 0xff) << 16;
+    // RS selects which block of 16 Aff0 values target_list refers to
+    let rs: u64 = (aff0 >> 4) << 44;
     let irm: u64 = 0 << 40;
     let sgi_id: u64 = sgi_num << 24;
-    let target_list: u64 = 1 << cpu_id;
-    let val: u64 = aff1 | aff2 | aff3 | irm | sgi_id | target_list;
+    let target_list: u64 = 1 << (aff0 & 0xf);
+    let val: u64 = aff1 | aff2 | aff3 | rs | irm | sgi_id | target_list;
     write_sysreg!(icc_sgi1r_el1, val);
     debug!("write sgi sys value = {:#x}", val);
 }
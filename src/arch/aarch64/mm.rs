@@ -4,9 +4,9 @@ use spin::RwLock;
 
 use crate::{
     arch::Stage2PageTable,
-    consts::MAX_CPU_NUM,
     error::HvResult,
-    memory::MemorySet,
+    memory::{MemorySet, VirtAddr},
+    percpu::cpu_num,
     wait_for,
 };
 
@@ -119,16 +119,16 @@ pub fn setup_parange() {
     drop(p);
 
     PARANGE_OK_CPUS.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
-    wait_for(|| PARANGE_OK_CPUS.load(core::sync::atomic::Ordering::SeqCst) < MAX_CPU_NUM as _);
+    wait_for(|| PARANGE_OK_CPUS.load(core::sync::atomic::Ordering::SeqCst) < cpu_num() as _);
 }
 
 pub fn get_parange() -> u64 {
-    assert!(PARANGE_OK_CPUS.load(core::sync::atomic::Ordering::SeqCst) == MAX_CPU_NUM as _);
+    assert!(PARANGE_OK_CPUS.load(core::sync::atomic::Ordering::SeqCst) == cpu_num() as _);
     *MIN_PARANGE.read()
 }
 
 pub fn get_parange_bits() -> usize {
-    assert!(PARANGE_OK_CPUS.load(core::sync::atomic::Ordering::SeqCst) == MAX_CPU_NUM as _);
+    assert!(PARANGE_OK_CPUS.load(core::sync::atomic::Ordering::SeqCst) == cpu_num() as _);
     PARANGE_TABLE[*MIN_PARANGE.read() as usize]
 }
 
@@ -136,6 +136,33 @@ pub fn is_s2_pt_level3() -> bool {
     get_parange_bits() < 44
 }
 
+/// Number of VMIDs, 8 bit ones as VTCR_EL2.VS is left 0.
+pub fn num_vmids() -> usize {
+    1 << 8
+}
+
 pub fn new_s2_memory_set() -> MemorySet<Stage2PageTable> {
     MemorySet::new(if is_s2_pt_level3() { 3 } else { 4 })
 }
+
+/// Clean the data cache of `start..start + size` to the point of coherency, so that a
+/// guest running with its caches off sees what hvisor wrote there.
+pub fn dcache_clean_range(start: VirtAddr, size: usize) {
+    // CTR_EL0.DminLine is log2 of the number of words in the smallest cache line
+    let line_size = 4 << ((read_sysreg!(CTR_EL0) >> 16) & 0xf);
+    let mut addr = start & !(line_size - 1);
+    while addr < start + size {
+        unsafe { core::arch::asm!("dc cvac, {}", in(reg) addr) };
+        addr += line_size;
+    }
+    unsafe { core::arch::asm!("dsb sy") };
+}
+
+/// Invalidate all instruction caches in the inner shareable domain.
+pub fn icache_invalidate_all() {
+    unsafe {
+        core::arch::asm!("ic ialluis");
+        core::arch::asm!("dsb ish");
+        core::arch::asm!("isb");
+    }
+}
@@ -1,4 +1,5 @@
 pub mod cpu;
+pub mod dump;
 pub mod entry;
 pub mod ipi;
 pub mod mm;
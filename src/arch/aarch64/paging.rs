@@ -87,8 +87,12 @@ pub trait GenericPTE: Debug + Clone {
 const ENTRY_COUNT: usize = 512;
 
 pub trait PagingInstr {
-    unsafe fn activate(root_paddr: PhysAddr);
-    fn flush(vaddr: Option<usize>);
+    /// Switch to the page table at `root_paddr`, whose TLB entries are tagged
+    /// with `vmid`. Stage 1 page tables have no VMID.
+    unsafe fn activate(root_paddr: PhysAddr, vmid: usize);
+    /// Invalidate the TLB entries of `vaddr`, or all of them, tagged with `vmid`,
+    /// of the page table at `root_paddr`, or of one that is gone if `None`.
+    fn flush(vaddr: Option<usize>, root_paddr: Option<PhysAddr>, vmid: usize);
 }
 
 /// A basic read-only page table for address query only.
@@ -120,6 +124,8 @@ pub trait GenericPageTable: GenericPageTableImmut {
 
     unsafe fn activate(&self);
     fn flush(&self, vaddr: Option<Self::VA>);
+    /// Tag the TLB entries of this page table with `vmid` from now on.
+    fn set_vmid(&mut self, vmid: usize);
 }
 
 /// A immutable level-3/4 page table implements `GenericPageTableImmut`.
@@ -368,6 +374,8 @@ pub struct HvPageTable<VA, PTE: GenericPTE, I: PagingInstr> {
     inner: HvPageTableUnlocked<VA, PTE, I>,
     /// Make sure all accesses to the page table and its clonees is exclusive.
     clonee_lock: Arc<Mutex<()>>,
+    /// VMID of the TLB entries, 0 unless `set_vmid` is called.
+    vmid: usize,
 }
 
 impl<VA, PTE, I> HvPageTable<VA, PTE, I>
@@ -407,6 +415,7 @@ where
         Self {
             inner: HvPageTableUnlocked::from_root(root_paddr, level),
             clonee_lock: Arc::new(Mutex::new(())),
+            vmid: 0,
         }
     }
 
@@ -438,6 +447,7 @@ where
         Self {
             inner: HvPageTableUnlocked::new(level),
             clonee_lock: Arc::new(Mutex::new(())),
+            vmid: 0,
         }
     }
 
@@ -528,11 +538,15 @@ where
     }
 
     unsafe fn activate(&self) {
-        I::activate(self.root_paddr())
+        I::activate(self.root_paddr(), self.vmid)
     }
 
     fn flush(&self, vaddr: Option<Self::VA>) {
-        I::flush(vaddr.map(Into::into))
+        I::flush(vaddr.map(Into::into), Some(self.root_paddr()), self.vmid)
+    }
+
+    fn set_vmid(&mut self, vmid: usize) {
+        self.vmid = vmid;
     }
 }
 
@@ -209,14 +209,14 @@ impl fmt::Debug for PageTableEntry {
 pub struct S1PTInstr;
 
 impl PagingInstr for S1PTInstr {
-    unsafe fn activate(root_paddr: HostPhysAddr) {
+    unsafe fn activate(root_paddr: HostPhysAddr, _vmid: usize) {
         TTBR0_EL2.set(root_paddr as _);
         core::arch::asm!("isb");
         core::arch::asm!("tlbi alle2");
         core::arch::asm!("dsb nsh");
     }
 
-    fn flush(_vaddr: Option<usize>) {
+    fn flush(_vaddr: Option<usize>, _root_paddr: Option<PhysAddr>, _vmid: usize) {
         // do nothing
     }
 }
@@ -1,10 +1,10 @@
 #![allow(unused)]
-use aarch64_cpu::registers::VTTBR_EL2;
+use aarch64_cpu::registers::{Readable, Writeable, VTTBR_EL2};
 use core::fmt;
 use numeric_enum_macro::numeric_enum;
 
 use crate::consts::PAGE_SIZE;
-use crate::memory::addr::{GuestPhysAddr, HostPhysAddr, PhysAddr};
+use crate::memory::addr::{virt_to_phys, GuestPhysAddr, HostPhysAddr, PhysAddr};
 use crate::memory::MemFlags;
 
 use super::paging::{GenericPTE, HvPageTable, PagingInstr};
@@ -196,19 +196,65 @@ impl fmt::Debug for PageTableEntry {
     }
 }
 
+/// VMID field of VTTBR_EL2. VMIDs are 8 bits, as VTCR_EL2.VS is 0.
+const VTTBR_VMID_SHIFT: u64 = 48;
+
+/// Stage 2 root table without any mapping. Nothing can be cached from walking
+/// it, so it stands in for the page table of a VMID that is gone.
+#[repr(C, align(4096))]
+struct EmptyTable([u64; PAGE_SIZE / 8]);
+
+static EMPTY_S2_ROOT: EmptyTable = EmptyTable([0; PAGE_SIZE / 8]);
+
+/// Run `f` with VTTBR_EL2 set to the page table at `root_paddr` and `vmid`, as
+/// stage 2 TLB maintenance only applies to the current VMID. The table must be
+/// the one of `vmid`: the hardware may walk it meanwhile, and cache what it
+/// finds under `vmid`.
+fn with_vttbr(root_paddr: Option<PhysAddr>, vmid: usize, f: impl FnOnce()) {
+    let root_paddr =
+        root_paddr.unwrap_or_else(|| virt_to_phys(&EMPTY_S2_ROOT as *const _ as usize));
+    let vttbr = VTTBR_EL2.get();
+    let target = root_paddr as u64 | (vmid as u64) << VTTBR_VMID_SHIFT;
+    let switch = vttbr != target;
+    if switch {
+        VTTBR_EL2.set(target);
+        unsafe { core::arch::asm!("isb") };
+    }
+    f();
+    if switch {
+        VTTBR_EL2.set(vttbr);
+        unsafe { core::arch::asm!("isb") };
+    }
+}
+
 pub struct S2PTInstr;
 
 impl PagingInstr for S2PTInstr {
-    unsafe fn activate(root_paddr: HostPhysAddr) {
-        debug!("activating stage 2 page table at {:#x}", root_paddr);
-        VTTBR_EL2.set_baddr(root_paddr as _);
+    unsafe fn activate(root_paddr: HostPhysAddr, vmid: usize) {
+        debug!(
+            "activating stage 2 page table at {:#x}, vmid {}",
+            root_paddr, vmid
+        );
+        // every page table has its own VMID, so no TLB entries need to go
+        VTTBR_EL2.set(root_paddr as u64 | (vmid as u64) << VTTBR_VMID_SHIFT);
         core::arch::asm!("isb");
-        core::arch::asm!("tlbi vmalls12e1is");
-        core::arch::asm!("dsb nsh");
     }
 
-    fn flush(_vaddr: Option<usize>) {
-        // do nothing
+    fn flush(vaddr: Option<usize>, root_paddr: Option<PhysAddr>, vmid: usize) {
+        with_vttbr(root_paddr, vmid, || unsafe {
+            core::arch::asm!("dsb ishst");
+            match vaddr {
+                Some(ipa) => {
+                    core::arch::asm!("tlbi ipas2e1is, {}", in(reg) ipa >> 12);
+                    // entries combining stage 1 and 2 are not found by IPA
+                    core::arch::asm!("dsb ish");
+                    core::arch::asm!("tlbi vmalle1is");
+                }
+                None => core::arch::asm!("tlbi vmalls12e1is"),
+            }
+            core::arch::asm!("dsb ish");
+            core::arch::asm!("isb");
+        });
     }
 }
 
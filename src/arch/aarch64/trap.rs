@@ -2,19 +2,24 @@ use aarch64_cpu::{asm::wfi, registers::*};
 use core::arch::global_asm;
 
 use crate::{
-    arch::{
-        cpu::mpidr_to_cpuid,
-        sysreg::{read_sysreg, write_sysreg},
-    },
+    arch::{ipi::arch_send_event, sysreg::read_sysreg},
+    config::{ABORT_POLICY_CRASH, ABORT_POLICY_INJECT},
+    consts::INVALID_ADDRESS,
     device::irqchip::gicv3::gicv3_handle_irq_el1,
+    error::HvErrorNum,
     event::{send_event, IPI_EVENT_SHUTDOWN, IPI_EVENT_WAKEUP},
     hypercall::{HyperCall, SGI_IPI_ID},
     memory::{mmio_handle_access, MMIOAccess},
     percpu::{get_cpu_data, this_cpu_data, this_zone, PerCpu},
-    zone::{is_this_root_zone, remove_zone},
+    stats::ExitClass,
+    trace::{trace, TRACE_EXIT, TRACE_PSCI},
+    zone::{
+        is_this_root_zone, lock_zone_management_while, remove_zone, this_zone_crash, zone_restart,
+        Zone, ZoneState,
+    },
 };
 
-use super::cpu::GeneralRegisters;
+use super::{cpu::GeneralRegisters, zone::HvArchZoneFault};
 
 global_asm!(
     include_str!("./trap.S"),
@@ -39,6 +44,8 @@ pub mod SmcType {
 
 const PSCI_VERSION_1_1: u64 = 0x10001;
 const PSCI_TOS_NOT_PRESENT_MP: u64 = 2;
+const PSCI_INVALID_PARAMETERS: u64 = -2i64 as u64;
+const PSCI_DENIED: u64 = -3i64 as u64;
 const ARM_SMCCC_VERSION_1_0: u64 = 0x10000;
 
 extern "C" {
@@ -61,6 +68,7 @@ pub mod PsciFnId {
     pub const PSCI_AFFINITY_INFO_32: u64 = 0x84000004;
     pub const PSCI_MIG_INFO_TYPE: u64 = 0x84000006;
     pub const PSCI_SYSTEM_OFF: u64 = 0x84000008;
+    pub const PSCI_SYSTEM_RESET: u64 = 0x84000009;
     pub const PSCI_FEATURES: u64 = 0x8400000a;
 
     pub const PSCI_CPU_SUSPEND_64: u64 = 0xc4000001;
@@ -83,16 +91,17 @@ pub enum TrapReturn {
 
 /*From hyp_vec->handle_vmexit x0:guest regs x1:exit_reason sp =stack_top-32*8*/
 pub fn arch_handle_exit(regs: &mut GeneralRegisters) -> ! {
-    let mpidr = MPIDR_EL1.get();
-    let _cpu_id = mpidr_to_cpuid(mpidr);
     trace!("cpu exit, exit_reson:{:#x?}", regs.exit_reason);
+    this_cpu_data().stats.exit_enter();
+    trace(TRACE_EXIT, regs.exit_reason as _, ESR_EL2.get());
     match regs.exit_reason as u64 {
         ExceptionType::EXIT_REASON_EL1_IRQ => irqchip_handle_irq1(),
         ExceptionType::EXIT_REASON_EL1_ABORT => arch_handle_trap_el1(regs),
         ExceptionType::EXIT_REASON_EL2_ABORT => arch_handle_trap_el2(regs),
         ExceptionType::EXIT_REASON_EL2_IRQ => irqchip_handle_irq2(),
-        _ => arch_dump_exit(regs.exit_reason),
+        _ => arch_dump_exit(regs),
     }
+    this_cpu_data().stats.exit_leave();
     unsafe { vmreturn(regs as *const _ as usize) }
 }
 
@@ -127,8 +136,7 @@ fn arch_handle_trap_el1(regs: &mut GeneralRegisters) {
                 ESR_EL2.read(ESR_EL2::EC)
             );
             error!("esr_el2: iss {:#x?}", ESR_EL2.read(ESR_EL2::ISS));
-            loop {}
-            // ret = TrapReturn::TrapUnhandled;
+            guest_fault(regs);
         }
     }
 }
@@ -165,18 +173,16 @@ fn arch_handle_trap_el2(_regs: &mut GeneralRegisters) {
     loop {}
 }
 
-fn handle_iabt(_regs: &mut GeneralRegisters) {
+fn handle_iabt(regs: &mut GeneralRegisters) {
     let iss = ESR_EL2.read(ESR_EL2::ISS);
     let op = iss >> 6 & 0x1;
     let hpfar = read_sysreg!(HPFAR_EL2);
     let hdfar = read_sysreg!(FAR_EL2);
     let mut address = hpfar << 8;
     address |= hdfar & 0xfff;
-    error!("error ins access {} at {:#x?}!", op, address);
-    error!("esr_el2: iss {:#x?}", iss);
-    loop {}
-    //TODO finish dabt handle
-    // arch_skip_instruction(frame);
+    warn!("error ins access {} at {:#x?}!", op, address);
+    warn!("esr_el2: iss {:#x?}", iss);
+    handle_unbacked_abort(regs, true);
 }
 fn handle_dabt(regs: &mut GeneralRegisters) {
     let iss = ESR_EL2.read(ESR_EL2::ISS);
@@ -190,6 +196,12 @@ fn handle_dabt(regs: &mut GeneralRegisters) {
     let far = read_sysreg!(FAR_EL2);
     let address = (far & 0xfff) | (hpfar << 8);
 
+    // DFSC of a permission fault, the first write to a page being dirty logged.
+    // The access is retried once the page is writable.
+    if iss & 0x3c == 0xc && this_zone().write().gpm.handle_dirty_write(address as _) {
+        return;
+    }
+
     let mut mmio_access = MMIOAccess {
         address: address as _,
         size,
@@ -213,8 +225,13 @@ fn handle_dabt(regs: &mut GeneralRegisters) {
                 regs.usr[srt as usize] = mmio_access.value as _;
             }
         }
+        Err(e) if e.num == HvErrorNum::EFAULT => {
+            handle_unbacked_abort(regs, false);
+            return;
+        }
         Err(e) => {
-            panic!("mmio_handle_access: {:#x?}", e);
+            error!("mmio_handle_access: {:#x?}", e);
+            guest_fault(regs);
         }
     }
     //TODO finish dabt handle
@@ -224,6 +241,7 @@ fn handle_dabt(regs: &mut GeneralRegisters) {
 fn handle_sysreg(regs: &mut GeneralRegisters) {
     //TODO check sysreg type
     //send sgi
+    this_cpu_data().stats.record(ExitClass::Sysreg);
     trace!("esr_el2: iss {:#x?}", ESR_EL2.read(ESR_EL2::ISS));
     let rt = (ESR_EL2.get() >> 5) & 0x1f;
     let val = regs.usr[rt as usize];
@@ -233,12 +251,39 @@ fn handle_sysreg(regs: &mut GeneralRegisters) {
         warn!("skip send sgi {:#x?}", sgi_id);
     } else {
         trace!("send sgi {:#x?}", sgi_id);
-        write_sysreg!(icc_sgi1r_el1, val);
+        send_guest_sgi(&this_zone().read(), val);
     }
 
     arch_skip_instruction(regs); //skip sgi write
 }
 
+/// Send the SGI of an ICC_SGI1R_EL1 write `val` by `zone`. Targets outside the
+/// zone are dropped.
+fn send_guest_sgi(zone: &Zone, val: u64) {
+    let sgi_id = (val >> 24) & 0xf;
+    if val & (1 << 40) != 0 {
+        // IRM, all the other cpus of the zone
+        for cpu_id in zone.cpu_set.iter_except(this_cpu_data().id) {
+            arch_send_event(cpu_id as _, sgi_id);
+        }
+        return;
+    }
+
+    let aff3 = ((val >> 48) & 0xff) << 32;
+    let aff2 = ((val >> 32) & 0xff) << 16;
+    let aff1 = ((val >> 16) & 0xff) << 8;
+    let rs = (val >> 44) & 0xf;
+    for bit in (0..16).filter(|bit| val & (1 << bit) != 0) {
+        let mpidr = aff3 | aff2 | aff1 | (rs << 4) | bit;
+        match zone.mpidr_to_cpu(mpidr) {
+            Some(cpu_id) if zone.cpu_set.contains_cpu(cpu_id) => {
+                arch_send_event(cpu_id as _, sgi_id)
+            }
+            _ => warn!("drop sgi {} to cpu {:#x} outside the zone", sgi_id, mpidr),
+        }
+    }
+}
+
 fn handle_hvc(regs: &mut GeneralRegisters) {
     /*
     if ESR_EL2.read(ESR_EL2::ISS) != 0x4a48 {
@@ -247,6 +292,7 @@ fn handle_hvc(regs: &mut GeneralRegisters) {
     */
     let (code, arg0, arg1) = (regs.usr[0], regs.usr[1], regs.usr[2]);
     let cpu_data = this_cpu_data();
+    cpu_data.stats.record(ExitClass::Hvc);
 
     debug!(
         "HVC from CPU{},code:{:#x?},arg0:{:#x?},arg1:{:#x?}",
@@ -266,6 +312,7 @@ fn handle_hvc(regs: &mut GeneralRegisters) {
 fn handle_smc(regs: &mut GeneralRegisters) {
     let (code, arg0, arg1, arg2) = (regs.usr[0], regs.usr[1], regs.usr[2], regs.usr[3]);
     let cpu_data = this_cpu_data() as &mut PerCpu;
+    cpu_data.stats.record(ExitClass::Smc);
     info!(
         "SMC from CPU{}, func_id:{:#x?}, arg0:{:#x?}, arg1:{:#x?}, arg2:{:#x?}",
         cpu_data.id, code, arg0, arg1, arg2
@@ -295,14 +342,26 @@ fn psci_emulate_features_info(code: u64) -> u64 {
         | PsciFnId::PSCI_AFFINITY_INFO_32
         | PsciFnId::PSCI_AFFINITY_INFO_64
         | PsciFnId::PSCI_FEATURES
+        | PsciFnId::PSCI_SYSTEM_RESET
         | SMCccFnId::SMCCC_VERSION => 0,
         _ => !0,
     }
 }
 
 fn psci_emulate_cpu_on(regs: &mut GeneralRegisters) -> u64 {
-    // Todo: Check if `cpu` is in the cpuset of current zone
-    let cpu = mpidr_to_cpuid(regs.usr[1]);
+    let zone = this_zone();
+    let zone_r = zone.read();
+    let cpu = match zone_r.mpidr_to_cpu(regs.usr[1]) {
+        Some(cpu) if zone_r.cpu_set.contains_cpu(cpu) => cpu,
+        Some(cpu) => {
+            error!("psci: cpu {} is not in zone {}", cpu, zone_r.id);
+            return PSCI_DENIED;
+        }
+        None => {
+            error!("psci: no cpu with mpidr {:#x}", regs.usr[1]);
+            return PSCI_INVALID_PARAMETERS;
+        }
+    };
     info!("psci: try to wake up cpu {}", cpu);
 
     let target_data = get_cpu_data(cpu as _);
@@ -321,6 +380,24 @@ fn psci_emulate_cpu_on(regs: &mut GeneralRegisters) -> u64 {
     0
 }
 
+/// Convert an error of the firmware to the value returned to the guest.
+fn psci_error_code(err: psci::error::Error) -> u64 {
+    use psci::error::{self, Error};
+    let code = match err {
+        Error::NotSupported => error::NOT_SUPPORTED,
+        Error::InvalidParameters => error::INVALID_PARAMETERS,
+        Error::Denied => error::DENIED,
+        Error::AlreadyOn => error::ALREADY_ON,
+        Error::OnPending => error::ON_PENDING,
+        Error::InternalFailure => error::INTERNAL_FAILURE,
+        Error::NotPresent => error::NOT_PRESENT,
+        Error::Disabled => error::DISABLED,
+        Error::InvalidAddress => error::INVALID_ADDRESS,
+        Error::Unknown(code) => code,
+    };
+    code as i64 as u64
+}
+
 fn handle_psci_smc(
     regs: &mut GeneralRegisters,
     code: u64,
@@ -328,6 +405,7 @@ fn handle_psci_smc(
     _arg1: u64,
     _arg2: u64,
 ) -> u64 {
+    trace(TRACE_PSCI, code, arg0);
     match code {
         PsciFnId::PSCI_VERSION => PSCI_VERSION_1_1,
         PsciFnId::PSCI_CPU_SUSPEND_32 | PsciFnId::PSCI_CPU_SUSPEND_64 => {
@@ -336,10 +414,18 @@ fn handle_psci_smc(
             0
         },
         PsciFnId::PSCI_CPU_OFF_32 | PsciFnId::PSCI_CPU_OFF_64 => {
-            todo!();
+            this_cpu_data().arch_cpu.idle();
         }
         PsciFnId::PSCI_AFFINITY_INFO_32 | PsciFnId::PSCI_AFFINITY_INFO_64 => {
-            !get_cpu_data(arg0 as _).arch_cpu.psci_on as _
+            let zone = this_zone();
+            let zone_r = zone.read();
+            match zone_r.mpidr_to_cpu(arg0) {
+                Some(cpu) if zone_r.cpu_set.contains_cpu(cpu) => {
+                    !get_cpu_data(cpu).arch_cpu.psci_on as _
+                }
+                Some(_) => PSCI_DENIED,
+                None => PSCI_INVALID_PARAMETERS,
+            }
         }
         PsciFnId::PSCI_MIG_INFO_TYPE => PSCI_TOS_NOT_PRESENT_MP,
         PsciFnId::PSCI_FEATURES => psci_emulate_features_info(regs.usr[1]),
@@ -366,6 +452,55 @@ fn handle_psci_smc(
 
             this_cpu_data().arch_cpu.idle();
         }
+        PsciFnId::PSCI_SYSTEM_RESET => {
+            if is_this_root_zone() {
+                // only returns if the firmware refused to reset the system
+                return match psci::system_reset() {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        error!("psci: system reset failed: {:?}", e);
+                        psci_error_code(e)
+                    }
+                };
+            }
+
+            // When two cpus of the zone reset it at the same time, one of them
+            // gets the lock and restarts the zone, the other one gives up with
+            // DENIED as soon as it sees the zone restarting or restarted, it is
+            // reset anyway. The root zone shutting the zone down makes it give
+            // up too, the shutdown waits for this cpu.
+            let zone = this_zone();
+            let restarts = zone.read().restarts;
+            let superseded = || {
+                let zone_r = zone.read();
+                this_cpu_data().zone.is_none()
+                    || zone_r.state == ZoneState::Restarting
+                    || zone_r.restarts != restarts
+            };
+            let lock = match lock_zone_management_while(|| !superseded()) {
+                Some(lock) if !superseded() => lock,
+                _ => return PSCI_DENIED,
+            };
+            if let Err(e) = zone_restart(&zone) {
+                error!("psci: failed to reset zone: {:?}", e);
+                return PSCI_DENIED;
+            }
+            drop(lock);
+            let zone_r = zone.read();
+            let is_boot_cpu = zone_r.cpu_set.first_cpu() == Some(this_cpu_data().id);
+            let entry_point = zone_r.config.entry_point as usize;
+            drop(zone_r);
+            drop(zone);
+
+            let cpu_data = this_cpu_data();
+            if is_boot_cpu {
+                cpu_data.cpu_on_entry = entry_point;
+                cpu_data.arch_cpu.run();
+            } else {
+                cpu_data.cpu_on_entry = INVALID_ADDRESS;
+                cpu_data.arch_cpu.idle();
+            }
+        }
 
         _ => {
             warn!("unsupported smc standard service {:#x?}", code);
@@ -405,10 +540,82 @@ fn arch_skip_instruction(_regs: &mut GeneralRegisters) {
     ELR_EL2.set(pc);
 }
 
-fn arch_dump_exit(reason: u64) {
-    //TODO hypervisor coredump
-    error!("Unsupported Exit:{:#x?}, elr={:#x?}", reason, ELR_EL2.get());
-    loop {}
+fn arch_dump_exit(regs: &GeneralRegisters) -> ! {
+    error!(
+        "Unsupported Exit:{:#x?}, elr={:#x?}",
+        regs.exit_reason,
+        ELR_EL2.get()
+    );
+    guest_fault(regs);
+}
+
+/// An abort on an IPA that is neither RAM nor MMIO of the zone, handled as the
+/// `abort_policy` of the zone says.
+fn handle_unbacked_abort(regs: &GeneralRegisters, is_iabt: bool) {
+    this_cpu_data().stats.record(ExitClass::UnbackedAbort);
+    match this_zone().read().config.abort_policy {
+        ABORT_POLICY_INJECT => {}
+        ABORT_POLICY_CRASH => guest_fault(regs),
+        policy => {
+            warn!("unknown abort policy {}, injecting the abort", policy);
+        }
+    }
+    inject_abort(is_iabt);
+}
+
+/// Make the guest take a synchronous external abort at EL1 on the faulting
+/// instruction, as if its own memory system had reported it.
+fn inject_abort(is_iabt: bool) {
+    const ESR_EC_IABT_LOW: u64 = 0x20;
+    const ESR_EC_IABT_CUR: u64 = 0x21;
+    const ESR_EC_DABT_LOW: u64 = 0x24;
+    const ESR_EC_DABT_CUR: u64 = 0x25;
+    const ESR_IL: u64 = 1 << 25;
+    const ESR_FSC_EXTABT: u64 = 0x10;
+    // EL1h with DAIF masked, as on exception entry
+    const SPSR_EL1H_DAIF: u64 = 0x3c5;
+
+    let spsr = SPSR_EL2.get();
+    // the vector for the mode the exception was taken from, M[4:0] of SPSR
+    let (from_el0, vector) = match spsr & 0x1f {
+        0b00100 => (false, 0x000), // EL1t
+        0b00101 => (false, 0x200), // EL1h
+        0b00000 => (true, 0x400),  // EL0t
+        _ => (true, 0x600),        // AArch32 EL0
+    };
+    let ec = match (is_iabt, from_el0) {
+        (true, true) => ESR_EC_IABT_LOW,
+        (true, false) => ESR_EC_IABT_CUR,
+        (false, true) => ESR_EC_DABT_LOW,
+        (false, false) => ESR_EC_DABT_CUR,
+    };
+    let esr = (ec << 26) | (ESR_EL2.get() & ESR_IL) | ESR_FSC_EXTABT;
+    debug!(
+        "inject abort esr {:#x} far {:#x} elr {:#x}",
+        esr,
+        FAR_EL2.get(),
+        ELR_EL2.get()
+    );
+
+    ESR_EL1.set(esr);
+    FAR_EL1.set(FAR_EL2.get());
+    ELR_EL1.set(ELR_EL2.get());
+    SPSR_EL1.set(spsr);
+    ELR_EL2.set(VBAR_EL1.get() + vector);
+    SPSR_EL2.set(SPSR_EL1H_DAIF);
+}
+
+/// Give up on the guest after a trap hvisor can't handle, see `this_zone_crash`.
+fn guest_fault(regs: &GeneralRegisters) -> ! {
+    this_zone_crash(HvArchZoneFault {
+        cpu_id: this_cpu_data().id as _,
+        esr: ESR_EL2.get(),
+        far: FAR_EL2.get(),
+        hpfar: read_sysreg!(HPFAR_EL2),
+        elr: ELR_EL2.get(),
+        spsr: SPSR_EL2.get(),
+        regs: regs.usr,
+    })
 }
 
 #[naked]
@@ -1,7 +1,12 @@
 use core::panic;
 
-use alloc::vec::Vec;
+use alloc::{format, vec, vec::Vec};
+use fdt::{
+    writer::{DeviceTree, Node},
+    Fdt,
+};
 
+use super::cpu::{cpuid_to_mpidr, mpidr_to_cpuid, mpidr_to_vcpu, vcpu_to_mpidr};
 use crate::{
     config::*,
     device::virtio_trampoline::{mmio_virtio_handler, VIRTIO_BRIDGE},
@@ -10,24 +15,16 @@ use crate::{
     zone::Zone,
 };
 
+/// Largest guest dtb whose cpu nodes are fixed up, it's copied to the hvisor heap.
+const MAX_GUEST_DTB_SIZE: usize = 0x40000;
+
 impl Zone {
     pub fn pt_init(&mut self, mem_regions: &[HvConfigMemoryRegion]) -> HvResult {
         // The first memory region is used to map the guest physical memory.
 
         for mem_region in mem_regions.iter() {
-            let mut flags = MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE;
-            if mem_region.mem_type == MEM_TYPE_IO {
-                flags |= MemFlags::IO;
-            }
             match mem_region.mem_type {
-                MEM_TYPE_RAM | MEM_TYPE_IO => {
-                    self.gpm.insert(MemoryRegion::new_with_offset_mapper(
-                        mem_region.virtual_start as GuestPhysAddr,
-                        mem_region.physical_start as HostPhysAddr,
-                        mem_region.size as _,
-                        flags,
-                    ))?
-                }
+                MEM_TYPE_RAM | MEM_TYPE_IO => self.pt_map_region(mem_region)?,
                 MEM_TYPE_VIRTIO => {
                     self.mmio_region_register(
                         mem_region.physical_start as _,
@@ -46,16 +43,199 @@ impl Zone {
         Ok(())
     }
 
+    /// Map the RAM or IO `mem_region` into this zone.
+    pub fn pt_map_region(&mut self, mem_region: &HvConfigMemoryRegion) -> HvResult {
+        self.gpm.insert(MemoryRegion::new_with_offset_mapper(
+            mem_region.virtual_start as GuestPhysAddr,
+            mem_region.physical_start as HostPhysAddr,
+            mem_region.size as _,
+            mem_region_flags(mem_region),
+        ))
+    }
+
+    /// Unmap the RAM and IO regions of `mem_regions`, which belong to another
+    /// zone, from this zone, the root zone.
+    pub fn pt_unmap_zone_regions(&mut self, mem_regions: &[HvConfigMemoryRegion]) -> HvResult {
+        for (_, ipa, _, size) in self.root_overlaps(mem_regions) {
+            self.gpm.unmap_partial(ipa, size)?;
+        }
+        Ok(())
+    }
+
+    /// Map back the RAM and IO regions of `mem_regions` that were taken from this
+    /// zone, the root zone, by `pt_unmap_zone_regions`.
+    pub fn pt_remap_zone_regions(&mut self, mem_regions: &[HvConfigMemoryRegion]) -> HvResult {
+        for (flags, ipa, hpa, size) in self.root_overlaps(mem_regions) {
+            self.gpm
+                .insert(MemoryRegion::new_with_offset_mapper(ipa, hpa, size, flags))?;
+        }
+        Ok(())
+    }
+
+    /// Find the parts of the RAM and IO regions of this zone, the root zone, that are
+    /// used by the RAM and IO regions of `mem_regions`, as (flags, ipa, hpa, size).
+    fn root_overlaps(
+        &self,
+        mem_regions: &[HvConfigMemoryRegion],
+    ) -> Vec<(MemFlags, GuestPhysAddr, HostPhysAddr, usize)> {
+        let is_mapped = |region: &&HvConfigMemoryRegion| {
+            region.mem_type == MEM_TYPE_RAM || region.mem_type == MEM_TYPE_IO
+        };
+        let mut overlaps = Vec::new();
+        for root_region in self.config.memory_regions().iter().filter(is_mapped) {
+            let root_end = root_region.physical_start + root_region.size;
+            for region in mem_regions.iter().filter(is_mapped) {
+                let start = region.physical_start.max(root_region.physical_start);
+                let end = (region.physical_start + region.size).min(root_end);
+                if start < end {
+                    overlaps.push((
+                        mem_region_flags(root_region),
+                        (root_region.virtual_start + start - root_region.physical_start) as _,
+                        start as _,
+                        (end - start) as _,
+                    ));
+                }
+            }
+        }
+        overlaps
+    }
+
     pub fn mmio_init(&mut self, hv_config: &HvArchZoneConfig) {
         self.vgicv3_mmio_init(hv_config);
     }
+
+    /// The MPIDR affinity the zone sees for physical cpu `cpu_id`.
+    pub fn cpu_mpidr(&self, cpu_id: usize) -> Option<u64> {
+        if self.has_vcpu_ids() {
+            self.cpu_to_vcpu(cpu_id).map(vcpu_to_mpidr)
+        } else {
+            Some(cpuid_to_mpidr(cpu_id))
+        }
+    }
+
+    /// The physical cpu the zone refers to by `mpidr`.
+    pub fn mpidr_to_cpu(&self, mpidr: u64) -> Option<usize> {
+        if self.has_vcpu_ids() {
+            self.vcpu_to_cpu(mpidr_to_vcpu(mpidr)?)
+        } else {
+            mpidr_to_cpuid(mpidr)
+        }
+    }
+
+    /// Rewrite the cpu nodes of the dtb at `dtb_ipa` to the vCPUs of the zone. The
+    /// existing cpu nodes are reused in order, the extra ones are removed and the
+    /// missing ones are copied from the last one. `cpu-map` is removed, as it may
+    /// refer to the removed nodes. A dtb that grows may only take the RAM behind
+    /// it up to the kernel, other images loaded there must leave room for it.
+    pub fn fixup_guest_dtb_cpus(&self, dtb_ipa: usize) -> HvResult {
+        let mut header = [0u8; 8];
+        self.gpm.copy_from_guest(dtb_ipa, &mut header)?;
+        let total_size = u32::from_be_bytes(header[4..].try_into().unwrap()) as usize;
+        if total_size > MAX_GUEST_DTB_SIZE {
+            return hv_result_err!(E2BIG, format!("guest dtb of {:#x} bytes", total_size));
+        }
+        let mut dtb = vec![0u8; total_size];
+        self.gpm.copy_from_guest(dtb_ipa, &mut dtb)?;
+
+        let fdt =
+            Fdt::new(&dtb).map_err(|e| hv_err!(EINVAL, format!("invalid guest dtb: {:?}", e)))?;
+        let mut tree = DeviceTree::from_fdt(&fdt);
+        let cpus = tree
+            .node_mut("/cpus")
+            .ok_or(hv_err!(ENOENT, "no /cpus in guest dtb"))?;
+        let address_cells = cpus
+            .property("#address-cells")
+            .and_then(|p| Some(u32::from_be_bytes(p.value.get(..4)?.try_into().ok()?)))
+            .unwrap_or(2) as usize;
+
+        let is_cpu = |node: &Node| {
+            node.property("device_type")
+                .is_some_and(|p| p.value.as_slice() == b"cpu\0")
+        };
+        let mut cpu_nodes: Vec<Node> = cpus
+            .children
+            .iter()
+            .filter(|n| is_cpu(n))
+            .cloned()
+            .collect();
+        let template = match cpu_nodes.last() {
+            Some(node) => node.clone(),
+            None => return hv_result_err!(ENOENT, "no cpu nodes in guest dtb"),
+        };
+        cpu_nodes.truncate(self.num_vcpus());
+        while cpu_nodes.len() < self.num_vcpus() {
+            let mut node = template.clone();
+            node.remove_property("phandle");
+            node.remove_property("linux,phandle");
+            cpu_nodes.push(node);
+        }
+        for (vcpu_id, node) in cpu_nodes.iter_mut().enumerate() {
+            let mpidr = vcpu_to_mpidr(vcpu_id);
+            node.name = format!("cpu@{:x}", mpidr);
+            node.set_reg(address_cells, 0, &[(mpidr, 0)]);
+            node.remove_property("status");
+        }
+        cpus.children
+            .retain(|n| !is_cpu(n) && !n.name.starts_with("cpu-map"));
+        cpus.children.extend(cpu_nodes);
+
+        // the dtb can't be moved, it can only grow into the RAM behind it
+        let new_dtb = tree.to_dtb();
+        if new_dtb.len() > total_size && !self.guest_dtb_fits(dtb_ipa, new_dtb.len()) {
+            return hv_result_err!(
+                E2BIG,
+                format!(
+                    "guest dtb grows from {:#x} to {:#x} bytes, past the RAM it can take",
+                    total_size,
+                    new_dtb.len()
+                )
+            );
+        }
+        self.gpm.copy_to_guest(dtb_ipa, &new_dtb)?;
+        self.gpm.clean_guest_dcache(dtb_ipa, new_dtb.len())
+    }
+
+    /// If a dtb of `size` bytes at `dtb_ipa` stays inside the RAM of the zone and
+    /// doesn't reach the kernel loaded behind it.
+    fn guest_dtb_fits(&self, dtb_ipa: usize, size: usize) -> bool {
+        if let Some(kernel_ipa) = self.kernel_ipa {
+            if kernel_ipa > dtb_ipa && size > kernel_ipa - dtb_ipa {
+                return false;
+            }
+        }
+        self.config.ram_contains(dtb_ipa as _, size as _)
+    }
+}
+
+fn mem_region_flags(mem_region: &HvConfigMemoryRegion) -> MemFlags {
+    let mut flags = MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE;
+    if mem_region.mem_type == MEM_TYPE_IO {
+        flags |= MemFlags::IO;
+    }
+    flags
 }
 
 #[repr(C)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 pub struct HvArchZoneConfig {
     pub gicd_base: usize,
     pub gicr_base: usize,
     pub gicd_size: usize,
     pub gicr_size: usize,
 }
+
+/// What a cpu of a zone was doing when it took a fault hvisor can't handle,
+/// reported to the root zone by `HvZoneFault`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvArchZoneFault {
+    /// The physical cpu that took the fault.
+    pub cpu_id: u64,
+    pub esr: u64,
+    pub far: u64,
+    pub hpfar: u64,
+    pub elr: u64,
+    pub spsr: u64,
+    /// x0 to x30 of the guest.
+    pub regs: [u64; 31],
+}
@@ -1,8 +1,9 @@
 use super::csr::*;
 use crate::{
-    consts::{PER_CPU_ARRAY_PTR, PER_CPU_SIZE},
+    consts::{MAX_CPU_NUM, PER_CPU_ARRAY_PTR, PER_CPU_SIZE},
     memory::{PhysAddr, VirtAddr},
 };
+use alloc::vec::Vec;
 
 #[repr(C)]
 #[derive(Debug)]
@@ -109,6 +110,17 @@ pub fn this_cpu_id() -> usize {
     this_cpu_arch().get_cpuid()
 }
 
+/// Timebase frequency of the QEMU virt machine.
+const TIMEBASE_FREQ: u64 = 10_000_000;
+
+pub fn read_counter() -> u64 {
+    read_csr!(CSR_TIME) as _
+}
+
+pub fn counter_frequency() -> u64 {
+    TIMEBASE_FREQ
+}
+
 const HV_BASE: VirtAddr = 0x80200000;
 const HV_PHY_BASE: PhysAddr = 0x80200000;
 
@@ -117,3 +129,12 @@ pub fn cpu_start(cpuid: usize, start_addr: usize, opaque: usize) {
         panic!("cpu_start error: {:#x?}", e);
     }
 }
+
+/// The ids of the harts SBI `hart_get_status` knows about, assuming they are
+/// contiguous from 0.
+pub fn firmware_cpu_hw_ids() -> Vec<u64> {
+    (0..MAX_CPU_NUM)
+        .take_while(|&hartid| sbi_rt::hart_get_status(hartid).err().is_none())
+        .map(|hartid| hartid as u64)
+        .collect()
+}
@@ -3,10 +3,12 @@ pub const CSR_SCAUSE: u64 = 0x142;
 pub const CSR_STVEC: u64 = 0x105;
 pub const CSR_SCOUNTEREN: u64 = 0x106;
 pub const CSR_SEPC: u64 = 0x141;
+pub const CSR_STVAL: u64 = 0x143;
 pub const CSR_SSTATUS: u64 = 0x100;
 pub const CSR_SSCRATCH: u64 = 0x140;
 pub const CSR_SIE: u64 = 0x104;
 pub const CSR_SIP: u64 = 0x144;
+pub const CSR_TIME: u64 = 0xC01;
 pub const CSR_VSSTATUS: u64 = 0x200;
 pub const CSR_VSIE: u64 = 0x204;
 pub const CSR_VSTVEC: u64 = 0x205;
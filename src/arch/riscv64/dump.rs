@@ -0,0 +1,77 @@
+//! State of the current cpu for the panic report.
+
+use super::{
+    cpu::{this_cpu_id, ArchCpu},
+    csr::{read_csr, CSR_HTINST, CSR_HTVAL, CSR_SCAUSE, CSR_SEPC, CSR_SSTATUS, CSR_STVAL},
+};
+use crate::{
+    consts::{core_end, PER_CPU_SIZE},
+    symbols,
+};
+
+/// Frames followed at most, in case the frame records are corrupted.
+const MAX_FRAMES: usize = 32;
+
+/// The registers of the last trap taken to HS-mode, the counterpart of EL2.
+pub fn dump_el2_regs() {
+    println!(
+        "scause: {:#018x}  sepc:   {:#018x}  sstatus: {:#018x}",
+        read_csr!(CSR_SCAUSE),
+        read_csr!(CSR_SEPC),
+        read_csr!(CSR_SSTATUS)
+    );
+    println!(
+        "stval:  {:#018x}  htval:  {:#018x}  htinst:  {:#018x}",
+        read_csr!(CSR_STVAL),
+        read_csr!(CSR_HTVAL),
+        read_csr!(CSR_HTINST)
+    );
+}
+
+pub fn dump_guest_regs(cpu: &ArchCpu) {
+    for (i, regs) in cpu.x.chunks(4).enumerate() {
+        for (j, reg) in regs.iter().enumerate() {
+            print!("x{:<2}: {:#018x}  ", i * 4 + j, reg);
+        }
+        println!();
+    }
+}
+
+/// Walk the frame records from the caller, hvisor is built with frame pointers.
+/// s0 points right above a frame record: the return address at s0 - 8 and the
+/// caller's s0 at s0 - 16.
+pub fn dump_backtrace() {
+    let mut fp: usize;
+    unsafe { core::arch::asm!("mv {}, s0", out(reg) fp) };
+
+    if !symbols::available() {
+        println!("backtrace (no symbol table, see scripts/gen_symbols.py):");
+    } else {
+        println!("backtrace:");
+    }
+    // only the stack of this cpu, the number of cpus may not be known yet
+    let stack_bottom = core_end() + this_cpu_id() * PER_CPU_SIZE;
+    let stacks = stack_bottom..stack_bottom + PER_CPU_SIZE;
+    for i in 0..MAX_FRAMES {
+        let record = fp.wrapping_sub(16);
+        if fp % 8 != 0 || !stacks.contains(&record) || !stacks.contains(&(record + 8)) {
+            break;
+        }
+        let (next_fp, ra) = unsafe { (*(record as *const usize), *((record + 8) as *const usize)) };
+        if ra == 0 {
+            break;
+        }
+        // the call is the instruction before the return address, it may be a
+        // compressed one, so take an address that is inside it either way
+        let pc = ra - 2;
+        match symbols::lookup(pc) {
+            Some((name, offset)) => {
+                println!("  #{:<2} {:#018x} {}+{:#x}", i, pc, name, offset);
+            }
+            None => {
+                println!("  #{:<2} {:#018x} <unknown>", i, pc);
+            }
+        }
+        fp = next_fp;
+    }
+}
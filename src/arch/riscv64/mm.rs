@@ -1,10 +1,13 @@
 use spin::RwLock;
 
+use super::csr::{read_csr, write_csr, CSR_HGATP};
+
 use crate::{
     arch::s1pt::Stage1PageTable,
     error::HvResult,
     memory::{
-        addr::align_up, GuestPhysAddr, HostPhysAddr, MemFlags, MemoryRegion, MemorySet, HV_PT,
+        addr::align_up, GuestPhysAddr, HostPhysAddr, MemFlags, MemoryRegion, MemorySet, VirtAddr,
+        HV_PT,
     },
 };
 
@@ -120,3 +123,27 @@ pub fn init_hv_page_table(fdt: &fdt::Fdt) -> HvResult {
     HV_PT.call_once(|| RwLock::new(hv_pt));
     Ok(())
 }
+
+/// Number of VMIDs, found by writing all ones to the VMID field of hgatp, which
+/// keeps only the implemented VMIDLEN bits.
+pub fn num_vmids() -> usize {
+    let hgatp = read_csr!(CSR_HGATP);
+    write_csr!(CSR_HGATP, 0x3fff << 44);
+    let vmid_mask = (read_csr!(CSR_HGATP) >> 44) & 0x3fff;
+    write_csr!(CSR_HGATP, hgatp);
+    vmid_mask + 1
+}
+
+/// Make what hvisor wrote to `start..start + size` visible to a guest running
+/// with its caches off. The harts hvisor runs on keep their caches coherent
+/// with memory, so ordering the writes is enough.
+pub fn dcache_clean_range(_start: VirtAddr, _size: usize) {
+    unsafe { core::arch::asm!("fence rw, rw") };
+}
+
+/// Make the instructions written to memory visible to the instruction fetches
+/// of this hart. `fence.i` can't reach the other harts, a guest image must be
+/// loaded before they first run it.
+pub fn icache_invalidate_all() {
+    unsafe { core::arch::asm!("fence.i") };
+}
@@ -1,5 +1,6 @@
 pub mod cpu;
 pub mod csr;
+pub mod dump;
 pub mod entry;
 pub mod mm;
 pub mod paging;
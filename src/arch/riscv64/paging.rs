@@ -89,8 +89,12 @@ pub trait GenericPTE: Debug + Clone {
 const ENTRY_COUNT: usize = 512;
 
 pub trait PagingInstr {
-    unsafe fn activate(root_paddr: PhysAddr);
-    fn flush(vaddr: Option<usize>);
+    /// Switch to the page table at `root_paddr`, whose TLB entries are tagged
+    /// with `vmid`. Stage 1 page tables have no VMID.
+    unsafe fn activate(root_paddr: PhysAddr, vmid: usize);
+    /// Invalidate the TLB entries of `vaddr`, or all of them, tagged with `vmid`,
+    /// of the page table at `root_paddr`, or of one that is gone if `None`.
+    fn flush(vaddr: Option<usize>, root_paddr: Option<PhysAddr>, vmid: usize);
 }
 
 /// A basic read-only page table for address query only.
@@ -119,6 +123,8 @@ pub trait GenericPageTable: GenericPageTableImmut {
 
     unsafe fn activate(&self);
     fn flush(&self, vaddr: Option<Self::VA>);
+    /// Tag the TLB entries of this page table with `vmid` from now on.
+    fn set_vmid(&mut self, vmid: usize);
 }
 
 /// A immutable level-3 page table implements `GenericPageTableImmut`.
@@ -349,6 +355,8 @@ pub struct Level3PageTable<VA, PTE: GenericPTE, I: PagingInstr> {
     inner: Level3PageTableUnlocked<VA, PTE, I>,
     /// Make sure all accesses to the page table and its clonees is exclusive.
     clonee_lock: Arc<Mutex<()>>,
+    /// VMID of the TLB entries, 0 unless `set_vmid` is called.
+    vmid: usize,
 }
 
 impl<VA, PTE, I> Level3PageTable<VA, PTE, I>
@@ -390,6 +398,7 @@ where
         Self {
             inner: Level3PageTableUnlocked::from_root(root_paddr),
             clonee_lock: Arc::new(Mutex::new(())),
+            vmid: 0,
         }
     }
 
@@ -413,6 +422,7 @@ where
         Self {
             inner: Level3PageTableUnlocked::new(),
             clonee_lock: Arc::new(Mutex::new(())),
+            vmid: 0,
         }
     }
 
@@ -503,11 +513,15 @@ where
     }
 
     unsafe fn activate(&self) {
-        I::activate(self.root_paddr())
+        I::activate(self.root_paddr(), self.vmid)
     }
 
     fn flush(&self, vaddr: Option<Self::VA>) {
-        I::flush(vaddr.map(Into::into))
+        I::flush(vaddr.map(Into::into), Some(self.root_paddr()), self.vmid)
+    }
+
+    fn set_vmid(&mut self, vmid: usize) {
+        self.vmid = vmid;
     }
 }
 
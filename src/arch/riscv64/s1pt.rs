@@ -150,7 +150,7 @@ impl fmt::Debug for PageTableEntry {
 pub struct S1PTInstr;
 
 impl PagingInstr for S1PTInstr {
-    unsafe fn activate(root_paddr: HostPhysAddr) {
+    unsafe fn activate(root_paddr: HostPhysAddr, _vmid: usize) {
         info!("activate hv stage 1 page table");
         unsafe {
             satp::set(satp::Mode::Sv39, 0, root_paddr >> 12);
@@ -158,7 +158,7 @@ impl PagingInstr for S1PTInstr {
         }
     }
 
-    fn flush(_vaddr: Option<usize>) {
+    fn flush(_vaddr: Option<usize>, _root_paddr: Option<PhysAddr>, _vmid: usize) {
         // do nothing
     }
 }
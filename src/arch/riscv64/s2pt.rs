@@ -152,12 +152,11 @@ impl fmt::Debug for PageTableEntry {
 pub struct S2PTInstr;
 
 impl PagingInstr for S2PTInstr {
-    unsafe fn activate(root_paddr: HostPhysAddr) {
+    unsafe fn activate(root_paddr: HostPhysAddr, vmid: usize) {
         println!("guest stage2 PT activate");
         unsafe {
             let mut bits = 0usize;
             let mode: usize = 8; //Mode::Sv39x4
-            let vmid: usize = 0;
             bits.set_bits(60..64, mode as usize);
             bits.set_bits(44..58, vmid);
             bits.set_bits(0..44, root_paddr >> 12);
@@ -167,8 +166,18 @@ impl PagingInstr for S2PTInstr {
         }
     }
 
-    fn flush(_vaddr: Option<usize>) {
-        // do nothing
+    fn flush(vaddr: Option<usize>, _root_paddr: Option<PhysAddr>, vmid: usize) {
+        // hfence.gvma, which the assembler doesn't know without the H extension
+        unsafe {
+            match vaddr {
+                Some(gpa) => core::arch::asm!(
+                    ".insn r 0x73, 0x0, 0x31, x0, {}, {}",
+                    in(reg) gpa >> 2,
+                    in(reg) vmid
+                ),
+                None => core::arch::asm!(".insn r 0x73, 0x0, 0x31, x0, x0, {}", in(reg) vmid),
+            }
+        }
     }
 }
 
@@ -1,11 +1,30 @@
-use alloc::vec::Vec;
 use spin::Once;
 
-use crate::{arch::zone::HvArchZoneConfig, platform};
+use crate::{
+    arch::zone::HvArchZoneConfig,
+    consts::{CPU_MASK_WORDS, MAX_CPU_NUM},
+    error::HvResult,
+    memory::PhysAddr,
+    percpu::{cpu_num, CpuSet},
+    platform,
+};
 
 pub const MEM_TYPE_RAM: u32 = 0;
 pub const MEM_TYPE_IO: u32 = 1;
 pub const MEM_TYPE_VIRTIO: u32 = 2;
+/// RAM of `size` bytes at `virtual_start`, allocated by hvisor from the guest RAM pool.
+/// `physical_start` is ignored and filled in when the zone is created.
+pub const MEM_TYPE_RAM_ALLOC: u32 = 3;
+
+/// The RAM region keeps its contents when the zone is destroyed, e.g. for a crash
+/// dump area. All other RAM of a zone is zeroed before it's handed out again.
+pub const MEM_FLAG_NO_SCRUB: u32 = 1 << 0;
+
+/// An access of the zone to an IPA that is neither RAM nor MMIO makes the guest
+/// take a synchronous external abort.
+pub const ABORT_POLICY_INJECT: u32 = 0;
+/// An access of the zone to an IPA that is neither RAM nor MMIO crashes the zone.
+pub const ABORT_POLICY_CRASH: u32 = 1;
 
 pub const CONFIG_MAX_MEMORY_REGIONS: usize = 16;
 pub const CONFIG_MAX_INTERRUPTS: usize = 32;
@@ -17,6 +36,8 @@ pub const CONFIG_MAX_INTERRUPTS: usize = 32;
 
 pub struct HvConfigMemoryRegion {
     pub mem_type: u32,
+    /// `MEM_FLAG_*`, in what used to be padding.
+    pub flags: u32,
     pub physical_start: u64,
     pub virtual_start: u64,
     pub size: u64,
@@ -26,6 +47,7 @@ impl HvConfigMemoryRegion {
     pub fn new_empty() -> Self {
         Self {
             mem_type: 0,
+            flags: 0,
             physical_start: 0,
             virtual_start: 0,
             size: 0,
@@ -34,27 +56,60 @@ impl HvConfigMemoryRegion {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 pub struct HvZoneConfig {
     pub zone_id: u32,
-    cpus: u64,
+    /// Number of words in `cpus`, in what used to be padding. Must be
+    /// `CPU_MASK_WORDS`, so a config built for another mask size is rejected
+    /// instead of being misread.
+    pub cpu_mask_words: u32,
+    /// Bit `id % 64` of word `id / 64` is set if cpu `id` belongs to the zone.
+    cpus: [u64; CPU_MASK_WORDS],
     num_memory_regions: u32,
     memory_regions: [HvConfigMemoryRegion; CONFIG_MAX_MEMORY_REGIONS],
     num_interrupts: u32,
     interrupts: [u32; CONFIG_MAX_INTERRUPTS],
     pub entry_point: u64,
+    /// Host physical address of the kernel, inside one of the memory regions.
     pub kernel_load_paddr: u64,
     pub kernel_size: u64,
+    /// Host physical address of the dtb, inside one of the memory regions.
     pub dtb_load_paddr: u64,
     pub dtb_size: u64,
+    /// `ABORT_POLICY_INJECT` or `ABORT_POLICY_CRASH`.
+    pub abort_policy: u32,
+    /// Interrupt of `interrupts` injected when RAM is added to or removed from the
+    /// running zone, 0 for none.
+    pub mem_hotplug_irq: u32,
+    /// Guest physical address of the `HvMemHotplugEvent` written before
+    /// `mem_hotplug_irq` is injected.
+    pub mem_hotplug_ipa: u64,
 
     pub arch: HvArchZoneConfig,
 }
 
+/// The images the root zone passes to `HvZoneStartImages`. The `*_src` fields are
+/// addresses in the root zone, the `*_load_ipa` fields guest physical addresses of
+/// the new zone. `kernel_load_paddr` and `dtb_load_paddr` of the zone config are
+/// not used. No initrd if `initrd_size` is 0.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvZoneImages {
+    pub kernel_src: u64,
+    pub kernel_size: u64,
+    pub kernel_load_ipa: u64,
+    pub dtb_src: u64,
+    pub dtb_size: u64,
+    pub dtb_load_ipa: u64,
+    pub initrd_src: u64,
+    pub initrd_size: u64,
+    pub initrd_load_ipa: u64,
+}
+
 impl HvZoneConfig {
     pub fn new(
         zone_id: u32,
-        cpus: u64,
+        cpus: [u64; CPU_MASK_WORDS],
         num_memory_regions: u32,
         memory_regions: [HvConfigMemoryRegion; CONFIG_MAX_MEMORY_REGIONS],
         num_interrupts: u32,
@@ -68,6 +123,7 @@ impl HvZoneConfig {
     ) -> Self {
         Self {
             zone_id,
+            cpu_mask_words: CPU_MASK_WORDS as u32,
             cpus,
             num_memory_regions,
             memory_regions,
@@ -78,6 +134,9 @@ impl HvZoneConfig {
             kernel_size,
             dtb_load_paddr,
             dtb_size,
+            abort_policy: ABORT_POLICY_INJECT,
+            mem_hotplug_irq: 0,
+            mem_hotplug_ipa: 0,
             arch,
         }
     }
@@ -89,6 +148,59 @@ impl HvZoneConfig {
         &self.memory_regions[..self.num_memory_regions as usize]
     }
 
+    pub fn memory_regions_mut(&mut self) -> &mut [HvConfigMemoryRegion] {
+        if self.num_memory_regions > CONFIG_MAX_MEMORY_REGIONS as u32 {
+            panic!("Too many memory regions");
+        }
+        &mut self.memory_regions[..self.num_memory_regions as usize]
+    }
+
+    /// The guest physical address the host physical address `paddr` is mapped at
+    /// by the memory regions, such as `kernel_load_paddr` or `dtb_load_paddr`.
+    pub fn ipa_of(&self, paddr: u64) -> Option<usize> {
+        self.memory_regions()
+            .iter()
+            .filter(|region| region.mem_type != MEM_TYPE_RAM_ALLOC)
+            .find(|region| {
+                region.physical_start <= paddr && paddr - region.physical_start < region.size
+            })
+            .map(|region| (region.virtual_start + paddr - region.physical_start) as usize)
+    }
+
+    /// Whether the guest range `ipa..ipa + size` lies inside one RAM region.
+    pub fn ram_contains(&self, ipa: u64, size: u64) -> bool {
+        self.memory_regions().iter().any(|region| {
+            (region.mem_type == MEM_TYPE_RAM || region.mem_type == MEM_TYPE_RAM_ALLOC)
+                && region.virtual_start <= ipa
+                && ipa
+                    .checked_add(size)
+                    .map_or(false, |end| end <= region.virtual_start + region.size)
+        })
+    }
+
+    /// Append `region`, added to the zone at runtime.
+    pub fn push_memory_region(&mut self, region: HvConfigMemoryRegion) -> HvResult {
+        let num = self.memory_regions().len();
+        if num == CONFIG_MAX_MEMORY_REGIONS {
+            return hv_result_err!(
+                E2BIG,
+                format!("zone {} has {} memory regions", self.zone_id, num)
+            );
+        }
+        self.memory_regions[num] = region;
+        self.num_memory_regions += 1;
+        Ok(())
+    }
+
+    /// Remove the memory region at `index`, the following ones move down.
+    pub fn remove_memory_region(&mut self, index: usize) -> HvConfigMemoryRegion {
+        let num = self.memory_regions().len();
+        let region = self.memory_regions[index];
+        self.memory_regions.copy_within(index + 1..num, index);
+        self.num_memory_regions -= 1;
+        region
+    }
+
     pub fn interrupts(&self) -> &[u32] {
         if self.num_interrupts > CONFIG_MAX_INTERRUPTS as u32 {
             panic!("Too many interrupts");
@@ -96,24 +208,48 @@ impl HvZoneConfig {
         &self.interrupts[..self.num_interrupts as usize]
     }
 
-    pub fn cpus(&self) -> Vec<u64> {
-        let mut v = Vec::new();
-        for i in 0..64u64 {
-            if (self.cpus >> i) & 1 == 1 {
-                v.push(i);
-            }
+    pub fn cpus(&self) -> CpuSet {
+        CpuSet::new(MAX_CPU_NUM - 1, self.cpus)
+    }
+
+    /// Check that `cpus` has the layout hvisor was built with and only names
+    /// cpus that are present.
+    pub fn check_cpus(&self) -> HvResult {
+        if self.cpu_mask_words != CPU_MASK_WORDS as u32 {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "zone {} has a cpu mask of {} words, expected {}",
+                    self.zone_id, self.cpu_mask_words, CPU_MASK_WORDS
+                )
+            );
+        }
+        match self.cpus().iter().find(|&cpu_id| cpu_id >= cpu_num()) {
+            Some(cpu_id) => hv_result_err!(
+                EINVAL,
+                format!(
+                    "zone {} has cpu {}, which does not exist",
+                    self.zone_id, cpu_id
+                )
+            ),
+            None => Ok(()),
         }
-        v
     }
 }
 
 pub static mut HV_ROOT_ZONE_CONFIG: Once<HvZoneConfig> = Once::new();
 
-pub fn init() {
-    unsafe { HV_ROOT_ZONE_CONFIG.call_once(|| platform::platform_root_zone_config()) };
+/// Build the root zone config, `host_dtb` is the device tree hvisor is booted with.
+pub fn init(host_dtb: PhysAddr) {
+    let config =
+        unsafe { HV_ROOT_ZONE_CONFIG.call_once(|| platform::platform_root_zone_config(host_dtb)) };
+    debug!("root zone config: {:#x?}", config);
 }
 
 pub fn root_zone_config() -> &'static HvZoneConfig {
-    init();
-    unsafe { HV_ROOT_ZONE_CONFIG.get().unwrap() }
+    unsafe {
+        HV_ROOT_ZONE_CONFIG
+            .get()
+            .expect("root zone config is not initialized")
+    }
 }
@@ -1,5 +1,6 @@
 use crate::memory::addr::VirtAddr;
 pub use crate::memory::PAGE_SIZE;
+use crate::percpu::cpu_num;
 
 /// Size of the hypervisor heap.
 pub const HV_HEAP_SIZE: usize = 1024 * 1024; // 1 MB
@@ -13,14 +14,25 @@ pub const PER_CPU_ARRAY_PTR: *mut VirtAddr = __core_end as _;
 
 pub const INVALID_ADDRESS: usize = usize::MAX;
 
-pub const MAX_CPU_NUM: usize = 4;
+/// Upper bound of the number of cpus, the number present is `percpu::cpu_num()`.
+pub const MAX_CPU_NUM: usize = 256;
+
+/// Number of `u64` words in a cpu mask, such as [`crate::percpu::CpuSet`] and
+/// the `cpus` of a zone config.
+pub const CPU_MASK_WORDS: usize = (MAX_CPU_NUM + u64::BITS as usize - 1) / u64::BITS as usize;
+
+pub fn hv_start() -> VirtAddr {
+    skernel as _
+}
 
 pub fn core_end() -> VirtAddr {
     __core_end as _
 }
 
+/// The per-cpu areas are sized by `percpu::cpu_num()`, so this and `hv_end` can
+/// only be called after `percpu::init_cpu_num`.
 pub fn mem_pool_start() -> VirtAddr {
-    core_end() + MAX_CPU_NUM * PER_CPU_SIZE
+    core_end() + cpu_num() * PER_CPU_SIZE
 }
 
 pub fn hv_end() -> VirtAddr {
@@ -28,5 +40,6 @@ pub fn hv_end() -> VirtAddr {
 }
 
 extern "C" {
+    fn skernel();
     fn __core_end();
 }
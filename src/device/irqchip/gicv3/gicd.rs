@@ -34,6 +34,8 @@ pub const GICD_SGIR: usize = 0x0f00;
 pub const GICD_CPENDSGIR: usize = 0x0f10;
 pub const GICD_SPENDSGIR: usize = 0x0f20;
 pub const GICD_IROUTER: usize = 0x6000;
+/// Interrupt_Routing_Mode of GICD_IROUTER, route to any one cpu.
+pub const GICD_IROUTER_IRM: usize = 1 << 31;
 
 pub const GICDV3_CIDR0: usize = 0xfff0;
 pub const GICDV3_PIDR0: usize = 0xffe0;
@@ -32,6 +32,7 @@ pub const GICR_ISACTIVER: usize = GICD_ISACTIVER;
 pub const GICR_ICACTIVER: usize = GICD_ICACTIVER;
 pub const GICR_IPRIORITYR: usize = GICD_IPRIORITYR;
 pub const GICR_ICFGR: usize = GICD_ICFGR;
+pub const GICR_TYPER_VLPIS: usize = 1 << 1;
 pub const GICR_TYPER_LAST: usize = 1 << 4;
 
 pub fn enable_ipi() {
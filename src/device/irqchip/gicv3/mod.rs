@@ -80,20 +80,23 @@ pub mod gicd;
 pub mod gicr;
 pub mod vgic;
 
+use alloc::vec::Vec;
 use core::arch::asm;
-use core::ptr::write_volatile;
-use core::sync::atomic::AtomicU64;
+use core::ptr::{read_volatile, write_volatile};
 
 use spin::Once;
 
 use self::gicd::{enable_gic_are_ns, GICD_ICACTIVER, GICD_ICENABLER};
-use self::gicr::enable_ipi;
+use self::gicr::{enable_ipi, GICR_TYPER, GICR_TYPER_LAST, GICR_TYPER_VLPIS};
+use crate::arch::aarch64::cpu::cpuid_to_mpidr;
 use crate::arch::aarch64::sysreg::{read_sysreg, smc_arg1, write_sysreg};
 use crate::config::root_zone_config;
-use crate::consts::MAX_CPU_NUM;
 
 use crate::event::check_events;
 use crate::hypercall::SGI_IPI_ID;
+use crate::percpu::{cpu_num, this_cpu_data};
+use crate::stats::ExitClass;
+use crate::trace::{trace, TRACE_IRQ_INJECT};
 use crate::zone::Zone;
 
 //TODO: add Distributor init
@@ -140,12 +143,9 @@ fn gicv3_clear_pending_irqs() {
     }
 }
 
-static TIMER_INTERRUPT_COUNTER: AtomicU64 = AtomicU64::new(0);
-// how often to print timer interrupt counter
-const TIMER_INTERRUPT_PRINT_TIMES: u64 = 50;
-
 pub fn gicv3_handle_irq_el1() {
     if let Some(irq_id) = pending_irq() {
+        this_cpu_data().stats.record(ExitClass::Irq(irq_id));
         // enum ipi_msg_type {
         //     IPI_WAKEUP,
         //     IPI_TIMER,
@@ -181,13 +181,6 @@ pub fn gicv3_handle_irq_el1() {
             warn!("skip sgi {}", irq_id);
             deactivate_irq(irq_id);
         } else {
-            if irq_id == 27 {
-                // virtual timer interrupt
-                TIMER_INTERRUPT_COUNTER.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
-                if TIMER_INTERRUPT_COUNTER.load(core::sync::atomic::Ordering::SeqCst) % TIMER_INTERRUPT_PRINT_TIMES == 0 {
-                    debug!("Virtual timer interrupt, counter = {}", TIMER_INTERRUPT_COUNTER.load(core::sync::atomic::Ordering::SeqCst));
-                }
-            }
             // debug!("spi/ppi get {}", irq_id);
             //inject phy irq
             if irq_id > 31 {
@@ -272,6 +265,7 @@ fn write_lr(id: usize, val: u64) {
 }
 
 pub fn inject_irq(irq_id: usize, is_hardware: bool) {
+    trace(TRACE_IRQ_INJECT, irq_id as _, is_hardware as _);
     // mask
     const LR_VIRTIRQ_MASK: usize = (1 << 32) - 1;
 
@@ -320,6 +314,8 @@ pub struct Gic {
     pub gicr_base: usize,
     pub gicd_size: usize,
     pub gicr_size: usize,
+    /// Redistributor of each logical cpu.
+    pub gicr_bases: Vec<usize>,
 }
 
 pub fn host_gicd_base() -> usize {
@@ -327,8 +323,8 @@ pub fn host_gicd_base() -> usize {
 }
 
 pub fn host_gicr_base(id: usize) -> usize {
-    assert!(id < MAX_CPU_NUM);
-    GIC.get().unwrap().gicr_base + id * PER_GICR_SIZE
+    assert!(id < cpu_num());
+    GIC.get().unwrap().gicr_bases[id]
 }
 
 pub fn host_gicd_size() -> usize {
@@ -363,10 +359,54 @@ pub fn primary_init_early() {
         gicr_base: root_config.arch.gicr_base,
         gicd_size: root_config.arch.gicd_size,
         gicr_size: root_config.arch.gicr_size,
+        gicr_bases: find_gicr_bases(root_config.arch.gicr_base, root_config.arch.gicr_size),
     });
     debug!("gic = {:#x?}", GIC.get().unwrap());
 }
 
+/// Find the redistributor of each cpu by matching GICR_TYPER.Affinity_Value
+/// with its MPIDR. Falls back to the `cpu`-th frame if there is no match.
+fn find_gicr_bases(gicr_base: usize, gicr_size: usize) -> Vec<usize> {
+    let mut frames = Vec::new();
+    let mut base = gicr_base;
+    while base < gicr_base + gicr_size {
+        let typer = unsafe { read_volatile((base + GICR_TYPER) as *const u64) } as usize;
+        frames.push((typer >> 32, base));
+        if typer & GICR_TYPER_LAST != 0 {
+            break;
+        }
+        // frames with VLPI support also have the VLPI and reserved pages
+        base += if typer & GICR_TYPER_VLPIS != 0 {
+            2 * PER_GICR_SIZE
+        } else {
+            PER_GICR_SIZE
+        };
+    }
+
+    (0..cpu_num())
+        .map(|cpu| {
+            let mpidr = cpuid_to_mpidr(cpu);
+            let affinity = gicr_typer_affinity(mpidr);
+            match frames.iter().find(|&&(aff, _)| aff == affinity) {
+                Some(&(_, base)) => base,
+                None => {
+                    warn!(
+                        "no redistributor found for cpu {} (mpidr {:#x})",
+                        cpu, mpidr
+                    );
+                    gicr_base + cpu * PER_GICR_SIZE
+                }
+            }
+        })
+        .collect()
+}
+
+/// GICR_TYPER.Affinity_Value of the redistributor of the cpu with `mpidr`.
+pub fn gicr_typer_affinity(mpidr: u64) -> usize {
+    let mpidr = mpidr as usize;
+    ((mpidr >> 8) & 0xff00_0000) | (mpidr & 0xff_ffff)
+}
+
 pub fn primary_init_late() {
     enable_gic_are_ns();
     enable_irqs();
@@ -2,7 +2,17 @@ use alloc::sync::Arc;
 
 use super::{gicd::GICD_LOCK, host_gicd_size, is_spi};
 use crate::{
-    arch::zone::HvArchZoneConfig, consts::MAX_CPU_NUM, device::irqchip::gicv3::{gicd::*, gicr::*, host_gicd_base, host_gicr_base, PER_GICR_SIZE}, error::HvResult, memory::{mmio_perform_access, MMIOAccess}, percpu::{get_cpu_data, this_zone}, zone::Zone
+    arch::{
+        cpu::{cpuid_to_mpidr, mpidr_to_cpuid, vcpu_to_mpidr},
+        zone::HvArchZoneConfig,
+    },
+    device::irqchip::gicv3::{
+        gicd::*, gicr::*, gicr_typer_affinity, host_gicd_base, host_gicr_base, GIC, PER_GICR_SIZE,
+    },
+    error::HvResult,
+    memory::{mmio_perform_access, MMIOAccess},
+    percpu::{get_cpu_data, this_zone},
+    zone::Zone,
 };
 
 pub fn reg_range(base: usize, n: usize, size: usize) -> core::ops::Range<usize> {
@@ -12,12 +22,14 @@ pub fn reg_range(base: usize, n: usize, size: usize) -> core::ops::Range<usize>
 impl Zone {
     pub fn vgicv3_mmio_init(&mut self, arch: &HvArchZoneConfig) {
         let gicd_base = if arch.gicd_base == 0 {host_gicd_base()} else {arch.gicd_base};
-        let gicr_base = if arch.gicr_base == 0 {host_gicr_base(0)} else {arch.gicr_base};
+        let gicr_base = if arch.gicr_base == 0 {GIC.get().unwrap().gicr_base} else {arch.gicr_base};
         let gicd_size = if arch.gicd_size == 0 {host_gicd_size()} else {arch.gicd_size};
 
         self.mmio_region_register(gicd_base, gicd_size, vgicv3_dist_handler, 0);
-        for cpu in 0..MAX_CPU_NUM {
-            let gicr_base = gicr_base + cpu * PER_GICR_SIZE;
+        // one frame for each vCPU, which are all the cpus for the root zone
+        for vcpu_id in 0..self.num_vcpus() {
+            let cpu = self.vcpu_to_cpu(vcpu_id).unwrap();
+            let gicr_base = gicr_base + vcpu_id * PER_GICR_SIZE;
             debug!("registering gicr {} at {:#x?}", cpu, gicr_base);
             self.mmio_region_register(gicr_base, PER_GICR_SIZE, vgicv3_redist_handler, cpu);
         }
@@ -40,12 +52,19 @@ impl Zone {
         }
     }
 
-    fn insert_irq_to_bitmap(&mut self, irq: u32) {
+    pub fn insert_irq_to_bitmap(&mut self, irq: u32) {
         assert!(irq < 1024); // 1024 is the maximum number of interrupts supported by GICv3 (GICD_TYPER.ITLinesNumber)
         let irq_index = irq / 32;
         let irq_bit = irq % 32;
         self.irq_bitmap[irq_index as usize] |= 1 << irq_bit;
     }
+
+    pub fn remove_irq_from_bitmap(&mut self, irq: u32) {
+        assert!(irq < 1024);
+        let irq_index = irq / 32;
+        let irq_bit = irq % 32;
+        self.irq_bitmap[irq_index as usize] &= !(1 << irq_bit);
+    }
 }
 
 fn restrict_bitmask_access(
@@ -113,10 +132,33 @@ pub fn vgicv3_redist_handler(mmio: &mut MMIOAccess, cpu: usize) -> HvResult {
     match mmio.address {
         GICR_TYPER => {
             mmio_perform_access(gicr_base, mmio);
-            if cpu == MAX_CPU_NUM - 1 {
+            let zone = this_zone();
+            let zone_r = zone.read();
+            let vcpu_id = zone_r.cpu_to_vcpu(cpu).unwrap();
+            if zone_r.has_vcpu_ids() {
+                // Processor_Number and Affinity_Value of the vCPU
+                mmio.value &= !(0xffff << 8);
+                mmio.value |= vcpu_id << 8;
+                if mmio.size == 8 {
+                    mmio.value &= 0xffff_ffff;
+                    mmio.value |= gicr_typer_affinity(vcpu_to_mpidr(vcpu_id)) << 32;
+                }
+            }
+            // the host frames may be in another order, or in several regions
+            mmio.value &= !GICR_TYPER_LAST;
+            if vcpu_id == zone_r.num_vcpus() - 1 {
                 mmio.value |= GICR_TYPER_LAST;
             }
         }
+        reg if reg == GICR_TYPER + 4 => {
+            mmio_perform_access(gicr_base, mmio);
+            let zone = this_zone();
+            let zone_r = zone.read();
+            if zone_r.has_vcpu_ids() {
+                let vcpu_id = zone_r.cpu_to_vcpu(cpu).unwrap();
+                mmio.value = gicr_typer_affinity(vcpu_to_mpidr(vcpu_id));
+            }
+        }
         GICR_IIDR | 0xffd0..=0xfffc => {
             // Read-only registers that might be used by a zone to find the redistributor corresponding to a CPU. Keep them accessible.
             mmio_perform_access(gicr_base, mmio);
@@ -154,6 +196,35 @@ fn vgicv3_handle_irq_ops(mmio: &mut MMIOAccess, irq: u32) -> HvResult {
     Ok(())
 }
 
+/// GICD_IROUTER holds an MPIDR affinity, which is a vCPU's for zones with vCPU
+/// ids. Only 64-bit accesses are translated.
+fn vgicv3_handle_irouter(mmio: &mut MMIOAccess, irq: u32) -> HvResult {
+    let zone = this_zone();
+    let zone_r = zone.read();
+    if !zone_r.has_vcpu_ids() || mmio.size != 8 {
+        drop(zone_r);
+        return vgicv3_handle_irq_ops(mmio, irq);
+    }
+
+    if mmio.is_write {
+        // 1-of-N routing could pick a cpu of another zone, use the boot cpu
+        let cpu = match mmio.value & GICD_IROUTER_IRM {
+            0 => zone_r.mpidr_to_cpu(mmio.value as _),
+            _ => None,
+        };
+        let cpu = cpu.unwrap_or_else(|| zone_r.cpu_set.first_cpu().unwrap());
+        mmio.value = cpuid_to_mpidr(cpu) as _;
+        drop(zone_r);
+        vgicv3_handle_irq_ops(mmio, irq)
+    } else {
+        drop(zone_r);
+        vgicv3_handle_irq_ops(mmio, irq)?;
+        let mpidr = mpidr_to_cpuid(mmio.value as _).and_then(|cpu| zone.read().cpu_mpidr(cpu));
+        mmio.value = mpidr.unwrap_or(0) as _;
+        Ok(())
+    }
+}
+
 fn vgicv3_dist_misc_access(mmio: &mut MMIOAccess, gicd_base: usize) -> HvResult {
     let reg = mmio.address;
     if reg_range(GICDV3_PIDR0, 4, 4).contains(&reg)
@@ -181,7 +252,7 @@ pub fn vgicv3_dist_handler(mmio: &mut MMIOAccess, _arg: usize) -> HvResult {
 
     match reg {
         reg if reg_range(GICD_IROUTER, 1024, 8).contains(&reg) => {
-            vgicv3_handle_irq_ops(mmio, (reg - GICD_IROUTER) as u32 / 8)
+            vgicv3_handle_irouter(mmio, (reg - GICD_IROUTER) as u32 / 8)
         }
         reg if reg_range(GICD_ITARGETSR, 1024, 1).contains(&reg) => {
             vgicv3_handle_irq_ops(mmio, (reg - GICD_ITARGETSR) as u32)
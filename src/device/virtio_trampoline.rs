@@ -11,6 +11,8 @@ use crate::device::irqchip::gicv3::inject_irq;
 use crate::event::send_event;
 use crate::event::IPI_EVENT_WAKEUP_VIRTIO_DEVICE;
 use crate::hypercall::SGI_IPI_ID;
+use crate::percpu::handle_cpu_requests;
+use crate::trace::{trace, TRACE_VIRTIO_REQ};
 use crate::zone::root_zone;
 use crate::zone::this_zone_id;
 use crate::{error::HvResult, memory::MMIOAccess};
@@ -59,6 +61,7 @@ pub fn mmio_virtio_handler(mmio: &mut MMIOAccess, base: usize) -> HvResult {
     let cpu_id = this_cpu_id() as usize;
     let old_cfg_flag = cfg_flags[cpu_id];
     dev.push_req(hreq);
+    trace(TRACE_VIRTIO_REQ, mmio.address as _, mmio.is_write as _);
     // If req list is empty, send sgi to root linux to wake up virtio device.
     if dev.need_wakeup() {
         let root_cpu = root_zone().read().cpu_set.first_cpu().unwrap();
@@ -70,6 +73,7 @@ pub fn mmio_virtio_handler(mmio: &mut MMIOAccess, base: usize) -> HvResult {
     if need_interrupt == 0 {
         // when virtio backend finish the req, it will add 1 to cfg_flag.
         while cfg_flags[cpu_id] == old_cfg_flag {
+            handle_cpu_requests();
             fence(Ordering::Acquire);
             count += 1;
             if count > 1000000 {
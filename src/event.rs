@@ -1,22 +1,42 @@
 use crate::{
-    arch::ipi::arch_send_event,
+    arch::{cpu::this_cpu_id, ipi::arch_send_event},
     device::{
         irqchip::gicv3::inject_irq,
         virtio_trampoline::{handle_virtio_irq, IRQ_WAKEUP_VIRTIO_DEVICE},
     },
-    percpu::this_cpu_data,
+    hypercall::SGI_IPI_ID,
+    panic::stop_this_cpu,
+    percpu::{handle_cpu_requests, this_cpu_data},
+    platform::IRQ_ZONE_FAULT,
+    stats::ExitClass,
 };
-use alloc::{collections::VecDeque, vec::Vec};
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::{Mutex, Once};
 
 pub const IPI_EVENT_WAKEUP: usize = 0;
 pub const IPI_EVENT_SHUTDOWN: usize = 1;
 pub const IPI_EVENT_VIRTIO_INJECT_IRQ: usize = 2;
 pub const IPI_EVENT_WAKEUP_VIRTIO_DEVICE: usize = 3;
+pub const IPI_EVENT_SUSPEND: usize = 4;
+pub const IPI_EVENT_ZONE_FAULT: usize = 5;
+pub const IPI_EVENT_STOP: usize = 6;
+pub const IPI_EVENT_CALL: usize = 7;
+pub const IPI_EVENT_MEM_HOTPLUG: usize = 8;
 static EVENT_MANAGER: Once<EventManager> = Once::new();
 
+/// A function `call_on_cpus` runs on other cpus.
+struct CrossCall {
+    func: fn(usize, usize),
+    args: (usize, usize),
+    /// Number of cpus which have not run it yet.
+    pending: AtomicUsize,
+}
+
 struct EventManager {
     pub inner: Vec<Mutex<VecDeque<usize>>>,
+    /// The calls each cpu has to run.
+    calls: Vec<Mutex<VecDeque<Arc<CrossCall>>>>,
 }
 
 impl EventManager {
@@ -26,7 +46,10 @@ impl EventManager {
             let v = Mutex::new(VecDeque::new());
             vs.push(v)
         }
-        Self { inner: vs }
+        Self {
+            inner: vs,
+            calls: (0..max_cpus).map(|_| Mutex::new(VecDeque::new())).collect(),
+        }
     }
 
     fn add_event(&self, cpu: usize, event_id: usize) -> Option<()> {
@@ -65,7 +88,14 @@ pub fn init(max_cpus: usize) {
 
 pub fn check_events() -> bool {
     let cpu_data = this_cpu_data();
-    match fetch_event(cpu_data.id) {
+    // the calls are run whichever event the SGI was sent for, as the
+    // `IPI_EVENT_CALL` may be queued behind another event
+    let called = run_cross_calls();
+    let event = fetch_event(cpu_data.id);
+    if let Some(event_id) = event {
+        cpu_data.stats.record(ExitClass::Event(event_id));
+    }
+    match event {
         Some(IPI_EVENT_WAKEUP) => {
             cpu_data.arch_cpu.run();
         }
@@ -80,7 +110,24 @@ pub fn check_events() -> bool {
             inject_irq(IRQ_WAKEUP_VIRTIO_DEVICE, false);
             true
         }
-        _ => false,
+        Some(IPI_EVENT_SUSPEND) => {
+            cpu_data.wait_for_resume();
+            true
+        }
+        Some(IPI_EVENT_ZONE_FAULT) => {
+            inject_irq(IRQ_ZONE_FAULT as _, false);
+            true
+        }
+        Some(IPI_EVENT_STOP) => stop_this_cpu(),
+        Some(IPI_EVENT_CALL) => true,
+        Some(IPI_EVENT_MEM_HOTPLUG) => {
+            // the zone may be gone by now
+            if let Some(zone) = &cpu_data.zone {
+                inject_irq(zone.read().config.mem_hotplug_irq as _, false);
+            }
+            true
+        }
+        _ => called,
     }
 }
 
@@ -88,3 +135,51 @@ pub fn send_event(cpu_id: usize, ipi_int_id: usize, event_id: usize) {
     add_event(cpu_id, event_id);
     arch_send_event(cpu_id as _, ipi_int_id as _);
 }
+
+/// Run `func(args.0, args.1)` on each of `cpus` and wait until all of them have. They run
+/// it from their SGI handler, or from a loop where they wait in hvisor, so the
+/// caller must not hold a lock they may be spinning on, such as the lock of
+/// their zone.
+pub fn call_on_cpus(
+    cpus: impl Iterator<Item = usize>,
+    func: fn(usize, usize),
+    args: (usize, usize),
+) {
+    let manager = EVENT_MANAGER.get().unwrap();
+    let cpus: Vec<usize> = cpus.collect();
+    let call = Arc::new(CrossCall {
+        func,
+        args,
+        pending: AtomicUsize::new(cpus.len()),
+    });
+    for &cpu_id in cpus.iter() {
+        manager.calls[cpu_id].lock().push_back(call.clone());
+        send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_CALL);
+    }
+    while call.pending.load(Ordering::Acquire) != 0 {
+        // the cpus may be waiting for this one the same way
+        handle_cpu_requests();
+        core::hint::spin_loop();
+    }
+}
+
+/// Run the calls queued for this cpu by `call_on_cpus`. Returns whether there
+/// were any.
+pub fn run_cross_calls() -> bool {
+    let calls = match EVENT_MANAGER.get() {
+        Some(manager) => &manager.calls[this_cpu_id()],
+        None => return false,
+    };
+    let mut called = false;
+    loop {
+        let call = calls.lock().pop_front();
+        match call {
+            Some(call) => {
+                (call.func)(call.args.0, call.args.1);
+                call.pending.fetch_sub(1, Ordering::Release);
+                called = true;
+            }
+            None => return called,
+        }
+    }
+}
@@ -1,16 +1,30 @@
 #![allow(dead_code)]
-use crate::config::HvZoneConfig;
+use crate::arch::mm::icache_invalidate_all;
+use crate::config::{HvConfigMemoryRegion, HvZoneConfig, HvZoneImages};
 use crate::consts::{INVALID_ADDRESS, PAGE_SIZE};
-use crate::device::virtio_trampoline::{VIRTIO_BRIDGE, MAX_DEVS, MAX_REQ, VIRTIO_IRQS};
+use crate::device::virtio_trampoline::{
+    VirtioBridge, MAX_DEVS, MAX_REQ, VIRTIO_BRIDGE, VIRTIO_IRQS,
+};
 use crate::error::HvResult;
-use crate::percpu::{get_cpu_data, PerCpu};
-use crate::zone::{find_zone, is_this_root_zone, remove_zone, zone_create};
+use crate::memory::addr::phys_to_virt;
+use crate::memory::{copy_from_guest, copy_slice_to_guest, copy_to_guest, MemFlags};
+use crate::percpu::{cpu_num, get_cpu_data, handle_cpu_requests, this_zone, PerCpu};
+use crate::trace::{self, trace, TRACE_HYPERCALL, TRACE_VIRTIO_RES};
+use crate::zone::{
+    find_zone, is_this_root_zone, lock_zone_management, remove_zone, resume_zone, suspend_zone,
+    zone_add_memory, zone_create, zone_discard, zone_fetch_dirty_log, zone_list_info,
+    zone_remove_memory, zone_restart, zone_start_dirty_log, zone_stop_dirty_log, HvDirtyLogBuffer,
+    HvZoneInfo, HvZoneListHeader, Zone, ZoneState, HV_ZONE_INFO_VERSION,
+};
 
 use crate::event::{send_event, IPI_EVENT_SHUTDOWN, IPI_EVENT_VIRTIO_INJECT_IRQ, IPI_EVENT_WAKEUP};
+use alloc::sync::Arc;
 use core::convert::TryFrom;
+use core::mem::size_of;
 use core::sync::atomic::{fence, Ordering};
 
 use numeric_enum_macro::numeric_enum;
+use spin::RwLock;
 
 numeric_enum! {
     #[repr(u64)]
@@ -20,10 +34,64 @@ numeric_enum! {
         HvVirtioInjectIrq = 1,
         HvZoneStart = 2,
         HvZoneShutdown = 3,
+        HvZoneList = 4,
+        HvZonePause = 5,
+        HvZoneResume = 6,
+        HvZoneRestart = 7,
+        HvZoneStartImages = 8,
+        HvZoneFault = 9,
+        HvCpuStats = 10,
+        HvTraceBuffer = 11,
+        HvZoneMemAdd = 12,
+        HvZoneMemRemove = 13,
+        HvDirtyLogStart = 14,
+        HvDirtyLogStop = 15,
+        HvDirtyLogFetch = 16,
     }
 }
 pub const SGI_IPI_ID: u64 = 7;
 
+impl HyperCallCode {
+    /// Whether the hypercall changes zones, see `lock_zone_management`.
+    fn manages_zones(self) -> bool {
+        matches!(
+            self,
+            Self::HvZoneStart
+                | Self::HvZoneShutdown
+                | Self::HvZonePause
+                | Self::HvZoneResume
+                | Self::HvZoneRestart
+                | Self::HvZoneStartImages
+                | Self::HvZoneMemAdd
+                | Self::HvZoneMemRemove
+                | Self::HvDirtyLogStart
+                | Self::HvDirtyLogStop
+                | Self::HvDirtyLogFetch
+        )
+    }
+}
+
+/// Size of the bounce buffer `HvZoneStartImages` copies images through.
+const IMAGE_COPY_CHUNK: usize = 0x10000;
+
+/// Copy the `(name, src, dst, size)` images from the root zone into `zone`, and
+/// make them visible to its instruction fetches.
+fn load_zone_images(root: &Zone, zone: &Zone, loads: &[(&str, u64, u64, u64)]) -> HvResult {
+    let mut buf = vec![0u8; IMAGE_COPY_CHUNK];
+    for &(name, src, dst, size) in loads.iter().filter(|load| load.3 != 0) {
+        info!("loading {} to {:#x}, size {:#x}", name, dst, size);
+        let (src, dst, size) = (src as usize, dst as usize, size as usize);
+        for offset in (0..size).step_by(IMAGE_COPY_CHUNK) {
+            let len = IMAGE_COPY_CHUNK.min(size - offset);
+            root.gpm.copy_from_guest(src + offset, &mut buf[..len])?;
+            zone.gpm.copy_to_guest(dst + offset, &buf[..len])?;
+        }
+        zone.gpm.clean_guest_dcache(dst, size)?;
+    }
+    icache_invalidate_all();
+    Ok(())
+}
+
 pub type HyperCallResult = HvResult<usize>;
 
 pub struct HyperCall<'a> {
@@ -35,7 +103,8 @@ impl<'a> HyperCall<'a> {
         Self { cpu_data }
     }
 
-    pub fn hypercall(&mut self, code: u64, arg0: u64, _arg1: u64) -> HyperCallResult {
+    pub fn hypercall(&mut self, code: u64, arg0: u64, arg1: u64) -> HyperCallResult {
+        trace(TRACE_HYPERCALL, code, arg0);
         let code = match HyperCallCode::try_from(code) {
             Ok(code) => code,
             Err(_) => {
@@ -43,13 +112,32 @@ impl<'a> HyperCall<'a> {
                 return Ok(0);
             }
         };
-        unsafe {
-            match code {
-                HyperCallCode::HvVirtioInit => self.hv_virtio_init(arg0),
-                HyperCallCode::HvVirtioInjectIrq => self.hv_virtio_inject_irq(),
-                HyperCallCode::HvZoneStart => self.hv_zone_start(&*(arg0 as *const HvZoneConfig)),
-                HyperCallCode::HvZoneShutdown => self.hv_zone_shutdown(arg0),
+        let _lock = if code.manages_zones() {
+            Some(lock_zone_management())
+        } else {
+            None
+        };
+        match code {
+            HyperCallCode::HvVirtioInit => self.hv_virtio_init(arg0),
+            HyperCallCode::HvVirtioInjectIrq => self.hv_virtio_inject_irq(),
+            HyperCallCode::HvZoneStart => {
+                let config = copy_from_guest::<HvZoneConfig>(arg0 as _)?;
+                self.hv_zone_start(&config)
             }
+            HyperCallCode::HvZoneShutdown => self.hv_zone_shutdown(arg0),
+            HyperCallCode::HvZoneList => self.hv_zone_list(arg0, arg1),
+            HyperCallCode::HvZonePause => self.hv_zone_pause(arg0),
+            HyperCallCode::HvZoneResume => self.hv_zone_resume(arg0),
+            HyperCallCode::HvZoneRestart => self.hv_zone_restart(arg0),
+            HyperCallCode::HvZoneStartImages => self.hv_zone_start_images(arg0, arg1),
+            HyperCallCode::HvZoneFault => self.hv_zone_fault(arg0, arg1),
+            HyperCallCode::HvCpuStats => self.hv_cpu_stats(arg0, arg1),
+            HyperCallCode::HvTraceBuffer => self.hv_trace_buffer(arg0),
+            HyperCallCode::HvZoneMemAdd => self.hv_zone_mem_add(arg0, arg1),
+            HyperCallCode::HvZoneMemRemove => self.hv_zone_mem_remove(arg0, arg1),
+            HyperCallCode::HvDirtyLogStart => self.hv_dirty_log_start(arg0, arg1),
+            HyperCallCode::HvDirtyLogStop => self.hv_dirty_log_stop(arg0, arg1),
+            HyperCallCode::HvDirtyLogFetch => self.hv_dirty_log_fetch(arg0, arg1),
         }
     }
 
@@ -62,8 +150,14 @@ impl<'a> HyperCall<'a> {
         if !is_this_root_zone() {
             return hv_result_err!(EPERM, "Init virtio over non-root zones: unsupported!");
         }
-        let shared_region_addr_pa = shared_region_addr as usize;
-        assert!(shared_region_addr_pa % PAGE_SIZE == 0);
+        if shared_region_addr as usize % PAGE_SIZE != 0 {
+            return hv_result_err!(EINVAL, "virtio shared region is not page aligned");
+        }
+        let shared_region_addr_pa = this_zone().read().gpm.translate_guest_range(
+            shared_region_addr as _,
+            size_of::<VirtioBridge>(),
+            MemFlags::READ | MemFlags::WRITE,
+        )?;
         // let offset = shared_region_addr_pa & (PAGE_SIZE - 1);
         // memory::hv_page_table()
         // 	.write()
@@ -76,7 +170,7 @@ impl<'a> HyperCall<'a> {
         // TODO: flush tlb
         VIRTIO_BRIDGE
             .lock()
-            .set_base_addr(shared_region_addr_pa as _);
+            .set_base_addr(phys_to_virt(shared_region_addr_pa));
         info!("hvisor device region base is {:#x?}", shared_region_addr_pa);
         HyperCallResult::Ok(0)
     }
@@ -96,6 +190,7 @@ impl<'a> HyperCall<'a> {
             let res_front = region.res_front as usize;
             let irq_id = region.res_list[res_front].irq_id as u64;
             let target_zone = region.res_list[res_front].target_zone;
+            trace(TRACE_VIRTIO_RES, target_zone as _, irq_id);
             // TODO: only the first cpu receives the irq, is that reasonable???
             let target_cpu = find_zone(target_zone as _)
                 .unwrap()
@@ -132,16 +227,98 @@ impl<'a> HyperCall<'a> {
                 "Start zone operation over non-root zones: unsupported!"
             );
         }
-        let zone = zone_create(config)?;
+        config.check_cpus()?;
+        // the load addresses are host physical addresses here
+        let zone = zone_create(
+            config,
+            config.ipa_of(config.kernel_load_paddr),
+            config.ipa_of(config.dtb_load_paddr),
+        )?;
+        self.zone_boot(zone)
+    }
+
+    /// Like `hv_zone_start`, but hvisor copies the images described by the `HvZoneImages`
+    /// at `images_addr` into the new zone itself, before starting it.
+    fn hv_zone_start_images(&mut self, config_addr: u64, images_addr: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Start zone operation over non-root zones: unsupported!"
+            );
+        }
+        let config = copy_from_guest::<HvZoneConfig>(config_addr as _)?;
+        let images = copy_from_guest::<HvZoneImages>(images_addr as _)?;
+        config.check_cpus()?;
+        info!("hv_zone_start_images: images: {:#x?}", images);
+        let loads = [
+            (
+                "kernel",
+                images.kernel_src,
+                images.kernel_load_ipa,
+                images.kernel_size,
+            ),
+            ("dtb", images.dtb_src, images.dtb_load_ipa, images.dtb_size),
+            (
+                "initrd",
+                images.initrd_src,
+                images.initrd_load_ipa,
+                images.initrd_size,
+            ),
+        ];
+
+        // check everything before the zone is created
+        if images.kernel_size == 0 || images.dtb_size == 0 {
+            return hv_result_err!(EINVAL, "hv_zone_start_images: no kernel or dtb");
+        }
+        let root_zone = this_zone();
+        for &(name, src, dst, size) in loads.iter().filter(|load| load.3 != 0) {
+            if !config.ram_contains(dst, size) {
+                return hv_result_err!(
+                    EINVAL,
+                    format!("{} {:#x}+{:#x} is not inside zone ram", name, dst, size)
+                );
+            }
+            root_zone
+                .read()
+                .gpm
+                .check_guest_range(src as _, size as _, MemFlags::READ)?;
+        }
+
+        let zone = zone_create(
+            &config,
+            Some(images.kernel_load_ipa as _),
+            Some(images.dtb_load_ipa as _),
+        )?;
+        let res = load_zone_images(&root_zone.read(), &zone.read(), &loads);
+        if let Err(e) = res {
+            zone_discard(zone);
+            return Err(e);
+        }
+
+        self.zone_boot(zone)
+    }
+
+    /// Wake up the boot cpu of a newly created zone. The zone is discarded if
+    /// it can't be started.
+    fn zone_boot(&mut self, zone: Arc<RwLock<Zone>>) -> HyperCallResult {
         let boot_cpu = zone.read().cpu_set.first_cpu().unwrap();
 
         let target_data = get_cpu_data(boot_cpu as _);
+        let res = zone.read().fixup_guest_dtb();
+        if let Err(e) = res {
+            error!("failed to fix up the cpus in the dtb of the zone: {:?}", e);
+            zone_discard(zone);
+            return Err(e);
+        }
         let _lock = target_data.ctrl_lock.lock();
 
         if !target_data.arch_cpu.psci_on {
             send_event(boot_cpu, SGI_IPI_ID as _, IPI_EVENT_WAKEUP);
+            zone.write().set_state(ZoneState::Running);
         } else {
             error!("hv_zone_start: cpu {} already on", boot_cpu);
+            drop(_lock);
+            zone_discard(zone);
             return hv_result_err!(EBUSY);
         };
         drop(_lock);
@@ -163,16 +340,36 @@ impl<'a> HyperCall<'a> {
             Some(zone) => zone,
             _ => return hv_result_err!(EEXIST),
         };
-        let zone_r = zone.read();
+        // the cpus of the zone may be waiting for its lock, it's not held while
+        // they are waited for
+        let (cpu_set, paused) = {
+            let zone_r = zone.read();
+            (zone_r.cpu_set, zone_r.state == ZoneState::Paused)
+        };
 
         // // return zone's cpus to root_zone
-        zone_r.cpu_set.iter().for_each(|cpu_id| {
+        cpu_set.iter().for_each(|cpu_id| {
             let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
             get_cpu_data(cpu_id).zone = None;
             get_cpu_data(cpu_id).cpu_on_entry = INVALID_ADDRESS;
             send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
         });
+        // a paused zone only sees the shutdown event after being released
+        if paused {
+            resume_zone(&zone);
+        }
+        // the memory of the zone goes back to the root zone, so wait until it is left
+        cpu_set.iter().for_each(|cpu_id| {
+            let psci_on = &get_cpu_data(cpu_id).arch_cpu.psci_on;
+            while unsafe { core::ptr::read_volatile(psci_on) } {
+                handle_cpu_requests();
+                core::hint::spin_loop();
+            }
+        });
 
+        // before the memory goes back to the root zone or the RAM pool
+        let zone_r = zone.read();
+        zone_r.scrub_ram();
         zone_r.arch_irqchip_reset();
 
         drop(zone_r);
@@ -181,4 +378,272 @@ impl<'a> HyperCall<'a> {
 
         HyperCallResult::Ok(0)
     }
+
+    /// Write the info of all zones into the buffer at `buf_addr` of `buf_size` bytes. The buffer
+    /// starts with a `HvZoneListHeader`, followed by as many `HvZoneInfo`s as fit.
+    /// Returns the number of zones in hvisor.
+    fn hv_zone_list(&self, buf_addr: u64, buf_size: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "List zone operation over non-root zones: unsupported!"
+            );
+        }
+        let header_size = size_of::<HvZoneListHeader>();
+        let entry_size = size_of::<HvZoneInfo>();
+        if (buf_size as usize) < header_size {
+            return hv_result_err!(EINVAL, "hv_zone_list: buffer too small");
+        }
+        let infos = zone_list_info();
+        let num_entries = infos
+            .len()
+            .min((buf_size as usize - header_size) / entry_size);
+        let header = HvZoneListHeader {
+            version: HV_ZONE_INFO_VERSION,
+            num_zones: infos.len() as _,
+            num_entries: num_entries as _,
+            entry_size: entry_size as _,
+        };
+        copy_to_guest(buf_addr as _, &header)?;
+        for (i, info) in infos.iter().take(num_entries).enumerate() {
+            copy_to_guest(buf_addr as usize + header_size + i * entry_size, info)?;
+        }
+        HyperCallResult::Ok(infos.len())
+    }
+
+    /// Freeze all cpus of a non-root zone without losing their state.
+    fn hv_zone_pause(&mut self, zone_id: u64) -> HyperCallResult {
+        info!("handle hvc zone pause, id={}", zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Pause zone operation over non-root zones: unsupported!"
+            );
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(EEXIST),
+        };
+        let mut zone_w = zone.write();
+        if zone_w.state != ZoneState::Running {
+            return hv_result_err!(EBUSY, format!("zone {} is {:?}", zone_id, zone_w.state));
+        }
+        zone_w.set_state(ZoneState::Paused);
+        drop(zone_w);
+
+        suspend_zone(&zone);
+        HyperCallResult::Ok(0)
+    }
+
+    /// Let a zone paused by `hv_zone_pause` continue.
+    fn hv_zone_resume(&mut self, zone_id: u64) -> HyperCallResult {
+        info!("handle hvc zone resume, id={}", zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Resume zone operation over non-root zones: unsupported!"
+            );
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(EEXIST),
+        };
+        let mut zone_w = zone.write();
+        if zone_w.state != ZoneState::Paused {
+            return hv_result_err!(EINVAL, format!("zone {} is not paused", zone_id));
+        }
+        zone_w.set_state(ZoneState::Running);
+        drop(zone_w);
+
+        resume_zone(&zone);
+        HyperCallResult::Ok(0)
+    }
+
+    /// Reboot a non-root zone from its entry point without destroying it.
+    fn hv_zone_restart(&mut self, zone_id: u64) -> HyperCallResult {
+        info!("handle hvc zone restart, id={}", zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Restart zone operation over non-root zones: unsupported!"
+            );
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(EEXIST),
+        };
+        zone_restart(&zone)?;
+        HyperCallResult::Ok(0)
+    }
+
+    /// Write the `HvArchZoneFault` that crashed a zone to `buf_addr`.
+    fn hv_zone_fault(&self, zone_id: u64, buf_addr: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Zone fault operation over non-root zones: unsupported!"
+            );
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(EEXIST),
+        };
+        let fault = zone.read().fault;
+        match fault {
+            Some(fault) => {
+                copy_to_guest(buf_addr as _, &fault)?;
+                HyperCallResult::Ok(0)
+            }
+            None => hv_result_err!(ENOENT, format!("zone {} has not crashed", zone_id)),
+        }
+    }
+
+    /// Write the `HvCpuStats` of cpu `cpu_id` to `buf_addr`. The counters keep
+    /// changing while they are copied, so they are not an exact snapshot.
+    fn hv_cpu_stats(&self, cpu_id: u64, buf_addr: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Cpu stats operation over non-root zones: unsupported!"
+            );
+        }
+        if cpu_id as usize >= cpu_num() {
+            return hv_result_err!(EINVAL, format!("no cpu {}", cpu_id));
+        }
+        copy_to_guest(buf_addr as _, &get_cpu_data(cpu_id as _).stats.counters)?;
+        HyperCallResult::Ok(0)
+    }
+
+    /// The physical address of the trace buffer of cpu `cpu_id`, which is mapped
+    /// into the root zone at the same address. See `trace.rs` for its format.
+    fn hv_trace_buffer(&self, cpu_id: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Trace buffer operation over non-root zones: unsupported!"
+            );
+        }
+        match trace::buffer_paddr(cpu_id as _) {
+            Some(paddr) => HyperCallResult::Ok(paddr),
+            None => hv_result_err!(EINVAL, format!("no cpu {}", cpu_id)),
+        }
+    }
+
+    /// Add the `HvConfigMemoryRegion` at `region_addr` to a running non-root zone,
+    /// see `zone_add_memory`.
+    fn hv_zone_mem_add(&mut self, zone_id: u64, region_addr: u64) -> HyperCallResult {
+        info!("handle hvc zone mem add, id={}", zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Zone memory operation over non-root zones: unsupported!"
+            );
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let region = copy_from_guest::<HvConfigMemoryRegion>(region_addr as _)?;
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(EEXIST),
+        };
+        zone_add_memory(&zone, region)?;
+        HyperCallResult::Ok(0)
+    }
+
+    /// Remove the RAM region with the `virtual_start` and `size` of the
+    /// `HvConfigMemoryRegion` at `region_addr` from a running non-root zone, see
+    /// `zone_remove_memory`.
+    fn hv_zone_mem_remove(&mut self, zone_id: u64, region_addr: u64) -> HyperCallResult {
+        info!("handle hvc zone mem remove, id={}", zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Zone memory operation over non-root zones: unsupported!"
+            );
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let region = copy_from_guest::<HvConfigMemoryRegion>(region_addr as _)?;
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(EEXIST),
+        };
+        zone_remove_memory(&zone, region.virtual_start, region.size)?;
+        HyperCallResult::Ok(0)
+    }
+
+    /// Start logging the pages zone `zone_id` writes to in its memory region at
+    /// `ipa`. The zone is parked for a moment if it's running.
+    fn hv_dirty_log_start(&mut self, zone_id: u64, ipa: u64) -> HyperCallResult {
+        info!("handle hvc dirty log start, id={}, ipa={:#x}", zone_id, ipa);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Dirty log operation over non-root zones: unsupported!"
+            );
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(EEXIST),
+        };
+        zone_start_dirty_log(&zone, ipa as _)?;
+        HyperCallResult::Ok(0)
+    }
+
+    /// Stop the dirty log started by `hv_dirty_log_start`.
+    fn hv_dirty_log_stop(&mut self, zone_id: u64, ipa: u64) -> HyperCallResult {
+        info!("handle hvc dirty log stop, id={}, ipa={:#x}", zone_id, ipa);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Dirty log operation over non-root zones: unsupported!"
+            );
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(EEXIST),
+        };
+        zone_stop_dirty_log(&zone, ipa as _)?;
+        HyperCallResult::Ok(0)
+    }
+
+    /// Write the dirty log of the region described by the `HvDirtyLogBuffer` at
+    /// `buf_addr` to its bitmap and clear it. Returns the number of dirty pages.
+    fn hv_dirty_log_fetch(&mut self, zone_id: u64, buf_addr: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Dirty log operation over non-root zones: unsupported!"
+            );
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let buf = copy_from_guest::<HvDirtyLogBuffer>(buf_addr as _)?;
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(EEXIST),
+        };
+        // fails before the log is cleared if the bitmap is too small
+        let max_words = buf.bitmap_size as usize / size_of::<u64>();
+        let bitmap = zone_fetch_dirty_log(&zone, buf.ipa as _, max_words)?;
+        // hvisor only runs little endian, the words are written as they are
+        copy_slice_to_guest(buf.bitmap_addr as _, &bitmap)?;
+        let dirty = bitmap.iter().map(|word| word.count_ones() as usize).sum();
+        HyperCallResult::Ok(dirty)
+    }
 }
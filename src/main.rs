@@ -36,16 +36,18 @@ mod memory;
 mod panic;
 mod percpu;
 mod platform;
+mod stats;
+mod symbols;
+mod trace;
 mod zone;
 mod config;
 
 use crate::arch::mm::setup_parange;
-use crate::consts::MAX_CPU_NUM;
 use arch::{cpu::cpu_start, entry::arch_entry};
 use config::root_zone_config;
-use zone::zone_create;
+use zone::{zone_create, ZoneState};
 use core::sync::atomic::{AtomicI32, AtomicU32, Ordering};
-use percpu::PerCpu;
+use percpu::{cpu_num, PerCpu};
 
 static INITED_CPUS: AtomicU32 = AtomicU32::new(0);
 static ENTERED_CPUS: AtomicU32 = AtomicU32::new(0);
@@ -77,7 +79,7 @@ fn wait_for_counter(counter: &AtomicU32, max_value: u32) {
     wait_for(|| counter.load(Ordering::Acquire) < max_value)
 }
 
-fn primary_init_early() {
+fn primary_init_early(host_dtb: usize) {
     extern "C" {
         fn __core_end();
     }
@@ -95,15 +97,31 @@ fn primary_init_early() {
         option_env!("VENDOR").unwrap_or(""),
         option_env!("STATS").unwrap_or("off"),
     );
+    if !symbols::available() {
+        // only `make` patches the table into the ELF
+        warn!("no symbol table in the image, see scripts/gen_symbols.py");
+    }
 
     memory::frame::init();
     memory::frame::test();
-    event::init(MAX_CPU_NUM);
+    memory::ram_pool::init(platform::guest_ram_pool());
+    trace::init(cpu_num());
+    event::init(cpu_num());
+    config::init(host_dtb);
 
     device::irqchip::primary_init_early();
     // crate::arch::mm::init_hv_page_table().unwrap();
 
-    zone_create(root_zone_config()).unwrap();
+    let config = root_zone_config();
+    let root_zone = zone_create(
+        config,
+        config.ipa_of(config.kernel_load_paddr),
+        config.ipa_of(config.dtb_load_paddr),
+    )
+    .unwrap();
+    // the root zone drains the trace buffers
+    trace::map_buffers(&mut root_zone.write().gpm).unwrap();
+    root_zone.write().set_state(ZoneState::Running);
     INIT_EARLY_OK.store(1, Ordering::Release);
 }
 
@@ -125,7 +143,7 @@ fn per_cpu_init(cpu: &mut PerCpu) {
 }
 
 fn wakeup_secondary_cpus(this_id: usize, host_dtb: usize) {
-    for cpu_id in 0..MAX_CPU_NUM {
+    for cpu_id in 0..cpu_num() {
         if cpu_id == this_id {
             continue;
         }
@@ -143,6 +161,11 @@ fn rust_main(cpuid: usize, host_dtb: usize) {
         is_primary = true;
         memory::heap::init();
         memory::heap::test();
+        let cpu_hw_ids = platform::cpu_hw_ids(host_dtb);
+        percpu::init_cpu_num(cpu_hw_ids.len());
+        #[cfg(target_arch = "aarch64")]
+        arch::cpu::init_cpu_mpidrs(cpu_hw_ids);
+        println!("{} CPUs present", cpu_num());
     }
 
     let cpu = PerCpu::new(cpuid);
@@ -157,8 +180,8 @@ fn rust_main(cpuid: usize, host_dtb: usize) {
     }
 
     ENTERED_CPUS.fetch_add(1, Ordering::SeqCst);
-    wait_for(|| PerCpu::entered_cpus() < MAX_CPU_NUM as _);
-    assert_eq!(PerCpu::entered_cpus(), MAX_CPU_NUM as _);
+    wait_for(|| PerCpu::entered_cpus() < cpu_num() as _);
+    assert_eq!(PerCpu::entered_cpus(), cpu_num() as _);
 
     println!(
         "{} CPU {} has entered.",
@@ -170,7 +193,7 @@ fn rust_main(cpuid: usize, host_dtb: usize) {
     setup_parange();
 
     if is_primary {
-        primary_init_early(); // create root zone here
+        primary_init_early(host_dtb); // create root zone here
     } else {
         wait_for_counter(&INIT_EARLY_OK, 1);
     }
@@ -179,7 +202,7 @@ fn rust_main(cpuid: usize, host_dtb: usize) {
     device::irqchip::percpu_init();
 
     INITED_CPUS.fetch_add(1, Ordering::SeqCst);
-    wait_for_counter(&INITED_CPUS, MAX_CPU_NUM as _);
+    wait_for_counter(&INITED_CPUS, cpu_num() as _);
 
     if is_primary {
         primary_init_late();
@@ -3,16 +3,22 @@
 //! Memory management.
 
 use alloc::collections::btree_map::{BTreeMap, Entry};
+use alloc::vec::Vec;
 use core::fmt::{Debug, Formatter, Result};
+use core::mem::{size_of, size_of_val, MaybeUninit};
+use core::slice;
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin::Once;
 
 use super::AlignedPage;
 use super::{mapper::Mapper, MemFlags};
-use crate::arch::paging::{GenericPageTable, PageSize, PagingResult};
+use crate::arch::mm::dcache_clean_range;
+use crate::arch::paging::{GenericPageTable, GenericPageTableImmut, PageSize, PagingResult};
 use crate::arch::Stage2PageTable;
 use crate::error::HvResult;
-use crate::memory::addr::is_aligned;
-use crate::memory::PhysAddr;
+use crate::memory::addr::{is_aligned, phys_to_virt};
+use crate::memory::{GuestPhysAddr, PhysAddr, PAGE_SIZE};
+use crate::percpu::this_zone;
 
 #[derive(Clone)]
 pub struct MemoryRegion<VA> {
@@ -28,6 +34,27 @@ where
 {
     regions: BTreeMap<PT::VA, MemoryRegion<PT::VA>>,
     pt: PT,
+    dirty_logs: Vec<DirtyLog>,
+}
+
+/// The pages of a region written since the log was last fetched, see
+/// `MemorySet::start_dirty_log`.
+struct DirtyLog {
+    start: usize,
+    size: usize,
+    /// Bit `i % 64` of word `i / 64` is set if page `i` of the region was written.
+    bitmap: Vec<AtomicU64>,
+}
+
+impl DirtyLog {
+    fn contains(&self, addr: usize) -> bool {
+        self.start <= addr && addr < self.start + self.size
+    }
+
+    fn mark(&self, addr: usize) {
+        let page = (addr - self.start) / PAGE_SIZE;
+        self.bitmap[page / 64].fetch_or(1 << (page % 64), Ordering::Relaxed);
+    }
 }
 
 impl<VA: From<usize> + Into<usize> + Copy> MemoryRegion<VA> {
@@ -59,6 +86,7 @@ where
         Self {
             regions: BTreeMap::new(),
             pt: PT::new(pt_level),
+            dirty_logs: Vec::new(),
         }
     }
 
@@ -66,6 +94,7 @@ where
         Self {
             regions: self.regions.clone(),
             pt: self.pt.clone(),
+            dirty_logs: Vec::new(),
         }
     }
 
@@ -107,6 +136,8 @@ where
         if let Entry::Occupied(e) = self.regions.entry(start) {
             self.pt.unmap(e.get())?;
             e.remove();
+            self.pt.flush(None);
+            self.dirty_logs.retain(|log| log.start != start.into());
             Ok(())
         } else {
             hv_result_err!(
@@ -119,6 +150,50 @@ where
         }
     }
 
+    /// Unmap `start..start + size` from this set. Regions only partly covered by
+    /// the range are split, and their remaining parts stay mapped.
+    pub fn unmap_partial(&mut self, start: PT::VA, size: usize) -> HvResult {
+        assert!(is_aligned(start.into()));
+        assert!(is_aligned(size));
+        let start: usize = start.into();
+        let end = start + size;
+        let overlapped: Vec<PT::VA> = self
+            .regions
+            .values()
+            .filter(|region| {
+                let region_start: usize = region.start.into();
+                region_start < end && start < region_start + region.size
+            })
+            .map(|region| region.start)
+            .collect();
+        for key in overlapped {
+            let region = self.regions.remove(&key).unwrap();
+            self.pt.unmap(&region)?;
+            let region_start: usize = region.start.into();
+            let region_end = region_start + region.size;
+            if region_start < start {
+                self.insert(MemoryRegion::new(
+                    region_start.into(),
+                    start - region_start,
+                    region.flags,
+                    region.mapper.clone(),
+                ))?;
+            }
+            if end < region_end {
+                self.insert(MemoryRegion::new(
+                    end.into(),
+                    region_end - end,
+                    region.flags,
+                    region.mapper.clone(),
+                ))?;
+            }
+            // the remaining parts are mapped writable again
+            self.dirty_logs.retain(|log| log.start != region_start);
+        }
+        self.pt.flush(None);
+        Ok(())
+    }
+
     pub fn clear(&mut self) {
         for region in self.regions.values() {
             self.pt.unmap(region).unwrap();
@@ -130,12 +205,259 @@ where
         self.pt.activate();
     }
 
+    /// Physical address of the root of the page table.
+    pub fn root_paddr(&self) -> PhysAddr {
+        self.pt.root_paddr()
+    }
+
+    /// Tag the TLB entries of this set with `vmid`, before it's first activated.
+    pub fn set_vmid(&mut self, vmid: usize) {
+        self.pt.set_vmid(vmid);
+    }
+
     pub unsafe fn page_table_query(
         &self,
         vaddr: PT::VA,
     ) -> PagingResult<(PhysAddr, MemFlags, PageSize)> {
         self.pt.query(vaddr)
     }
+
+    fn dirty_log(&self, addr: usize) -> Option<&DirtyLog> {
+        self.dirty_logs.iter().find(|log| log.contains(addr))
+    }
+
+    /// Walk the guest range `gaddr..gaddr + len` page by page, and call `f` with the host
+    /// physical address, the offset in the range and the length of each piece. Fails with
+    /// `EFAULT` before calling `f` if any page is unmapped, device memory or lacks `flags`.
+    /// Pages written by `f` are marked in the dirty log, if `flags` has `WRITE`.
+    fn for_each_guest_chunk(
+        &self,
+        gaddr: usize,
+        len: usize,
+        flags: MemFlags,
+        mut f: impl FnMut(PhysAddr, usize, usize),
+    ) -> HvResult {
+        let end = match gaddr.checked_add(len) {
+            Some(end) => end,
+            None => return hv_result_err!(EFAULT),
+        };
+        let mut chunks = Vec::new();
+        let mut addr = gaddr;
+        while addr < end {
+            let (paddr, mut page_flags, page_size) = unsafe { self.page_table_query(addr.into()) }
+                .map_err(|_| hv_err!(EFAULT, format!("guest address {:#x} not mapped", addr)))?;
+            // only the guest is kept from writing to pages being dirty logged
+            if self.dirty_log(addr).is_some() {
+                page_flags |= MemFlags::WRITE;
+            }
+            if !page_flags.contains(flags) || page_flags.contains(MemFlags::IO) {
+                return hv_result_err!(
+                    EFAULT,
+                    format!("guest address {:#x} not accessible: {:?}", addr, page_flags)
+                );
+            }
+            let len = (page_size as usize - page_size.page_offset(addr)).min(end - addr);
+            chunks.push((paddr, addr - gaddr, len));
+            addr += len;
+        }
+        for (paddr, offset, len) in chunks {
+            if flags.contains(MemFlags::WRITE) {
+                // a chunk never crosses a page
+                if let Some(log) = self.dirty_log(gaddr + offset) {
+                    log.mark(gaddr + offset);
+                }
+            }
+            f(paddr, offset, len);
+        }
+        Ok(())
+    }
+
+    /// Check that the guest range `gaddr..gaddr + len` is accessible with `flags`.
+    pub fn check_guest_range(&self, gaddr: usize, len: usize, flags: MemFlags) -> HvResult {
+        self.for_each_guest_chunk(gaddr, len, flags, |_, _, _| {})
+    }
+
+    /// Clean the data cache of the guest range `gaddr..gaddr + len` to the point of coherency.
+    pub fn clean_guest_dcache(&self, gaddr: usize, len: usize) -> HvResult {
+        self.for_each_guest_chunk(gaddr, len, MemFlags::empty(), |paddr, _, len| {
+            dcache_clean_range(phys_to_virt(paddr), len)
+        })
+    }
+
+    /// Copy `buf.len()` bytes from guest physical address `gaddr` into `buf`.
+    pub fn copy_from_guest(&self, gaddr: usize, buf: &mut [u8]) -> HvResult {
+        self.for_each_guest_chunk(gaddr, buf.len(), MemFlags::READ, |paddr, offset, len| {
+            let src = phys_to_virt(paddr) as *const u8;
+            unsafe { core::ptr::copy_nonoverlapping(src, buf[offset..].as_mut_ptr(), len) };
+        })
+    }
+
+    /// Copy `buf` to guest physical address `gaddr`.
+    pub fn copy_to_guest(&self, gaddr: usize, buf: &[u8]) -> HvResult {
+        self.for_each_guest_chunk(gaddr, buf.len(), MemFlags::WRITE, |paddr, offset, len| {
+            let dst = phys_to_virt(paddr) as *mut u8;
+            unsafe { core::ptr::copy_nonoverlapping(buf[offset..].as_ptr(), dst, len) };
+        })
+    }
+
+    /// Translate the guest range `gaddr..gaddr + len` which must be accessible with `flags`
+    /// and physically contiguous. Returns the host physical address of `gaddr`.
+    pub fn translate_guest_range(
+        &self,
+        gaddr: usize,
+        len: usize,
+        flags: MemFlags,
+    ) -> HvResult<PhysAddr> {
+        let mut start = None;
+        let mut contiguous = true;
+        self.for_each_guest_chunk(gaddr, len, flags, |paddr, offset, _| {
+            let start = *start.get_or_insert(paddr);
+            contiguous &= paddr == start + offset;
+        })?;
+        match start {
+            Some(start) if contiguous => Ok(start),
+            _ => hv_result_err!(
+                EFAULT,
+                format!("guest range {:#x}+{:#x} is not contiguous", gaddr, len)
+            ),
+        }
+    }
+}
+
+/// Read a `T` from guest physical address `gaddr` of the current zone.
+pub fn copy_from_guest<T: Copy>(gaddr: usize) -> HvResult<T> {
+    let mut val = MaybeUninit::<T>::zeroed();
+    let buf = unsafe { slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    this_zone().read().gpm.copy_from_guest(gaddr, buf)?;
+    Ok(unsafe { val.assume_init() })
+}
+
+/// Write `val` to guest physical address `gaddr` of the current zone.
+pub fn copy_to_guest<T: Copy>(gaddr: usize, val: &T) -> HvResult {
+    copy_slice_to_guest(gaddr, slice::from_ref(val))
+}
+
+/// Write `vals` one after another to guest physical address `gaddr` of the
+/// current zone.
+pub fn copy_slice_to_guest<T: Copy>(gaddr: usize, vals: &[T]) -> HvResult {
+    let buf = unsafe { slice::from_raw_parts(vals.as_ptr() as *const u8, size_of_val(vals)) };
+    this_zone().read().gpm.copy_to_guest(gaddr, buf)
+}
+
+impl MemorySet<Stage2PageTable> {
+    /// Start logging which pages of the region at `start` the guest writes to.
+    /// The region is mapped again write-protected and with 4K pages, so that
+    /// each first write to a page takes a permission fault, which is handled by
+    /// `handle_dirty_write`. The cpus of the zone must be parked meanwhile, and
+    /// their TLBs flushed afterwards.
+    pub fn start_dirty_log(&mut self, start: GuestPhysAddr) -> HvResult {
+        let region = match self.regions.get(&start) {
+            Some(region) => region.clone(),
+            None => {
+                return hv_result_err!(ENOENT, format!("no memory region starts from {:#x}", start))
+            }
+        };
+        if !region.flags.contains(MemFlags::WRITE) || region.flags.contains(MemFlags::IO) {
+            return hv_result_err!(
+                EINVAL,
+                format!("memory region at {:#x} is not writable RAM", start)
+            );
+        }
+        if self.dirty_log(start).is_some() {
+            return hv_result_err!(
+                EBUSY,
+                format!("memory region at {:#x} is already dirty logged", start)
+            );
+        }
+
+        let mut protected = region.clone();
+        protected.flags.remove(MemFlags::WRITE);
+        protected.flags.insert(MemFlags::NO_HUGEPAGES);
+        self.pt.unmap(&region)?;
+        self.pt.map(&protected)?;
+        self.pt.flush(None);
+        let num_pages = region.size / PAGE_SIZE;
+        self.dirty_logs.push(DirtyLog {
+            start,
+            size: region.size,
+            bitmap: (0..(num_pages + 63) / 64)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        });
+        Ok(())
+    }
+
+    /// Stop the dirty log of the region at `start`, which is mapped as before it
+    /// started again. The cpus of the zone must be parked meanwhile, and their
+    /// TLBs flushed afterwards.
+    pub fn stop_dirty_log(&mut self, start: GuestPhysAddr) -> HvResult {
+        let index = match self.dirty_logs.iter().position(|log| log.start == start) {
+            Some(index) => index,
+            None => return hv_result_err!(ENOENT, format!("no dirty log at {:#x}", start)),
+        };
+        self.dirty_logs.remove(index);
+        let region = self.regions[&start].clone();
+        self.pt.unmap(&region)?;
+        self.pt.map(&region)?;
+        self.pt.flush(None);
+        Ok(())
+    }
+
+    /// Called on a stage 2 permission fault of the guest at `gpa`. A write to a
+    /// page being dirty logged marks it, and the page stays writable until the
+    /// log is fetched. Returns whether the fault was handled, the guest just
+    /// retries the access then.
+    pub fn handle_dirty_write(&mut self, gpa: GuestPhysAddr) -> bool {
+        match self.dirty_log(gpa) {
+            Some(log) => log.mark(gpa),
+            None => return false,
+        }
+        let page = PageSize::Size4K.align_down(gpa);
+        // another cpu of the zone may have taken the same fault first
+        if let Ok((paddr, flags, _)) = self.pt.query(page) {
+            if !flags.contains(MemFlags::WRITE) {
+                self.pt.update(page, paddr, flags | MemFlags::WRITE).ok();
+            }
+        }
+        self.pt.flush(Some(page));
+        true
+    }
+
+    /// Take the dirty log of the region at `start`, at most `max_words` words of
+    /// bitmap, one bit per page as in `DirtyLog`. The log is cleared and the
+    /// pages marked in it are write-protected again. The other cpus of the zone
+    /// may still write to them through their TLBs without faulting, so their
+    /// TLBs must be flushed before the pages are read.
+    pub fn fetch_dirty_log(
+        &mut self,
+        start: GuestPhysAddr,
+        max_words: usize,
+    ) -> HvResult<Vec<u64>> {
+        let log = match self.dirty_logs.iter().find(|log| log.start == start) {
+            Some(log) => log,
+            None => return hv_result_err!(ENOENT, format!("no dirty log at {:#x}", start)),
+        };
+        if log.bitmap.len() > max_words {
+            return hv_result_err!(
+                E2BIG,
+                format!("dirty log at {:#x} has {} words", start, log.bitmap.len())
+            );
+        }
+        let bitmap: Vec<u64> = log
+            .bitmap
+            .iter()
+            .map(|word| word.swap(0, Ordering::Relaxed))
+            .collect();
+
+        for i in (0..bitmap.len() * 64).filter(|i| bitmap[i / 64] & 1 << (i % 64) != 0) {
+            let page = start + i * PAGE_SIZE;
+            if let Ok((paddr, flags, _)) = self.pt.query(page) {
+                self.pt.update(page, paddr, flags - MemFlags::WRITE).ok();
+            }
+        }
+        self.pt.flush(None);
+        Ok(bitmap)
+    }
 }
 
 impl<VA: Into<usize> + Copy> Debug for MemoryRegion<VA> {
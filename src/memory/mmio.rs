@@ -1,6 +1,10 @@
 use core::ptr;
 
-use crate::{error::HvResult, percpu::this_zone};
+use crate::{
+    error::HvResult,
+    percpu::{this_cpu_data, this_zone},
+    stats::ExitClass,
+};
 
 use super::GuestPhysAddr;
 
@@ -62,17 +66,19 @@ pub fn mmio_perform_access(base: usize, mmio: &mut MMIOAccess) {
     }
 }
 
+/// Let the handler of the zone's MMIO region emulate `mmio`, `EFAULT` if no region covers it.
 pub fn mmio_handle_access(mmio: &mut MMIOAccess) -> HvResult {
     let zone = this_zone();
     let res = zone.read().find_mmio_region(mmio.address, mmio.size);
     match res {
         Some((region, handler, arg)) => {
+            this_cpu_data().stats.record(ExitClass::Mmio(region.start));
             mmio.address -= region.start;
             handler(mmio, arg)
         }
         None => {
             warn!("Zone {} unhandled mmio fault {:#x?}", zone.read().id, mmio);
-            hv_result_err!(EINVAL)
+            hv_result_err!(EFAULT)
         }
     }
 }
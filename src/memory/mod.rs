@@ -4,6 +4,8 @@ pub mod heap;
 pub mod mapper;
 pub mod mm;
 pub mod mmio;
+pub mod ram_pool;
+pub mod vmid;
 
 use core::ops::{Deref, DerefMut};
 
@@ -11,7 +13,9 @@ use bitflags::bitflags;
 
 pub use addr::{GuestPhysAddr, GuestVirtAddr, HostPhysAddr, HostVirtAddr, PhysAddr, VirtAddr};
 pub use frame::Frame;
-pub use mm::{MemoryRegion, MemorySet, PARKING_INST_PAGE};
+pub use mm::{
+    copy_from_guest, copy_slice_to_guest, copy_to_guest, MemoryRegion, MemorySet, PARKING_INST_PAGE,
+};
 pub use mmio::*;
 use spin::{Once, RwLock};
 
@@ -0,0 +1,116 @@
+//! Allocation of large physical regions used as guest RAM.
+
+use alloc::collections::BTreeMap;
+use core::ops::Range;
+
+use spin::Mutex;
+
+use super::addr::{align_down, align_up, phys_to_virt, PhysAddr};
+use crate::arch::mm::dcache_clean_range;
+use crate::error::HvResult;
+
+/// Free ranges of the pool, as start -> size. Adjacent ranges are always merged.
+struct RamPool {
+    free: BTreeMap<PhysAddr, usize>,
+}
+
+/// A region of physical memory allocated from the guest RAM pool. The region is
+/// returned to the pool when dropped.
+#[derive(Debug)]
+pub struct RamRegion {
+    start_paddr: PhysAddr,
+    size: usize,
+}
+
+static RAM_POOL: Mutex<RamPool> = Mutex::new(RamPool::empty());
+
+impl RamPool {
+    const fn empty() -> Self {
+        Self {
+            free: BTreeMap::new(),
+        }
+    }
+
+    fn init(&mut self, base: PhysAddr, size: usize) {
+        let start = align_up(base);
+        let end = align_down(base + size);
+        if start < end {
+            self.free.insert(start, end - start);
+        }
+    }
+
+    /// First fit, `align` must be a power of two.
+    fn alloc(&mut self, size: usize, align: usize) -> Option<PhysAddr> {
+        let (&free_start, &free_size, start) =
+            self.free.iter().find_map(|(start, size_free)| {
+                let aligned = (start + align - 1) & !(align - 1);
+                (aligned + size <= start + size_free).then_some((start, size_free, aligned))
+            })?;
+        self.free.remove(&free_start);
+        if free_start < start {
+            self.free.insert(free_start, start - free_start);
+        }
+        let free_end = free_start + free_size;
+        if start + size < free_end {
+            self.free.insert(start + size, free_end - start - size);
+        }
+        trace!("Allocate ram region: {:#x?}", start..start + size);
+        Some(start)
+    }
+
+    fn dealloc(&mut self, mut start: PhysAddr, mut size: usize) {
+        trace!("Deallocate ram region: {:#x?}", start..start + size);
+        if let Some((&prev_start, &prev_size)) = self.free.range(..start).next_back() {
+            if prev_start + prev_size == start {
+                self.free.remove(&prev_start);
+                start = prev_start;
+                size += prev_size;
+            }
+        }
+        if let Some(next_size) = self.free.remove(&(start + size)) {
+            size += next_size;
+        }
+        self.free.insert(start, size);
+    }
+}
+
+impl RamRegion {
+    /// Allocate `size` bytes aligned to `align`, and fill them with zero.
+    pub fn new_zero(size: usize, align: usize) -> HvResult<Self> {
+        let size = align_up(size);
+        let start_paddr = RAM_POOL
+            .lock()
+            .alloc(size, align.max(super::PAGE_SIZE))
+            .ok_or(hv_err!(
+                ENOMEM,
+                format!("no {:#x} bytes left in guest ram pool", size)
+            ))?;
+        let start = phys_to_virt(start_paddr);
+        unsafe { core::ptr::write_bytes(start as *mut u8, 0, size) };
+        // the guest may read it with the caches off
+        dcache_clean_range(start, size);
+        Ok(Self { start_paddr, size })
+    }
+
+    /// Get the start physical address of this region.
+    pub fn start_paddr(&self) -> PhysAddr {
+        self.start_paddr
+    }
+
+    /// Get the size (in bytes) of this region.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for RamRegion {
+    fn drop(&mut self) {
+        RAM_POOL.lock().dealloc(self.start_paddr, self.size);
+    }
+}
+
+/// Initialize the guest RAM pool with the physical range `pool`.
+pub fn init(pool: Range<PhysAddr>) {
+    RAM_POOL.lock().init(pool.start, pool.end - pool.start);
+    info!("Guest ram pool initialization finished: {:#x?}", pool);
+}
@@ -0,0 +1,40 @@
+//! Allocation of the VMIDs that tag the stage 2 TLB entries of the zones.
+
+use alloc::collections::BTreeSet;
+
+use spin::Mutex;
+
+use crate::arch::{mm::num_vmids, paging::PagingInstr, s2pt::S2PTInstr};
+use crate::error::HvResult;
+
+/// The VMIDs in use. VMID 0 is never handed out, it's left to the stage 2 page
+/// tables which don't belong to a zone.
+static VMIDS: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+
+/// A VMID owned by a zone. The VMID is freed when dropped.
+#[derive(Debug)]
+pub struct Vmid(usize);
+
+impl Vmid {
+    /// Allocate the lowest free VMID.
+    pub fn new() -> HvResult<Self> {
+        let mut vmids = VMIDS.lock();
+        let vmid = (1..num_vmids())
+            .find(|vmid| !vmids.contains(vmid))
+            .ok_or(hv_err!(ENOMEM, "no VMID left"))?;
+        vmids.insert(vmid);
+        Ok(Self(vmid))
+    }
+
+    pub fn id(&self) -> usize {
+        self.0
+    }
+}
+
+impl Drop for Vmid {
+    fn drop(&mut self) {
+        // the next owner must not hit the TLB entries of the previous one
+        S2PTInstr::flush(None, None, self.0);
+        VMIDS.lock().remove(&self.0);
+    }
+}
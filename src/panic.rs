@@ -1,8 +1,73 @@
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    arch::{
+        cpu::this_cpu_id,
+        dump::{dump_backtrace, dump_el2_regs, dump_guest_regs},
+    },
+    event::{send_event, IPI_EVENT_STOP},
+    hypercall::SGI_IPI_ID,
+    percpu::{cpu_num, this_cpu_data},
+    INIT_EARLY_OK,
+};
+
+/// Set by the first cpu that panics, the report is only printed once.
+static PANICKED: AtomicBool = AtomicBool::new(false);
 
 #[panic_handler]
 
 fn on_panic(info: &PanicInfo) -> ! {
     error!("panic occurred: {:#?}", info);
+    // a panic while reporting, or on another cpu at the same time
+    if PANICKED.swap(true, Ordering::SeqCst) {
+        loop {}
+    }
+
+    println!("==================== hvisor panic ====================");
+    println!("cpu: {}", this_cpu_id());
+    // the per-cpu data, the zones and the events are set up by then
+    let inited = INIT_EARLY_OK.load(Ordering::Acquire) != 0;
+    if inited {
+        dump_cpu_state();
+    }
+    dump_el2_regs();
+    dump_backtrace();
+
+    if inited {
+        for cpu_id in (0..cpu_num()).filter(|&cpu_id| cpu_id != this_cpu_id()) {
+            send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_STOP);
+        }
+    }
     loop {}
 }
+
+/// Handle `IPI_EVENT_STOP` from a panicking cpu: report the state of this cpu
+/// and stop.
+pub fn stop_this_cpu() -> ! {
+    println!(
+        "-------------------- cpu {} stopped --------------------",
+        this_cpu_id()
+    );
+    dump_cpu_state();
+    dump_el2_regs();
+    loop {}
+}
+
+fn dump_cpu_state() {
+    let cpu_data = this_cpu_data();
+    // the zone may be locked by the code that panicked
+    match cpu_data.zone.as_ref().map(|zone| zone.try_read()) {
+        Some(Some(zone)) => {
+            println!("zone: {} ({:?})", zone.id, zone.state);
+        }
+        Some(None) => {
+            println!("zone: <locked>");
+        }
+        None => {
+            println!("zone: none");
+        }
+    }
+    println!("guest registers:");
+    dump_guest_regs(&cpu_data.arch_cpu);
+}
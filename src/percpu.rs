@@ -1,13 +1,18 @@
 use alloc::sync::Arc;
-use spin::{Mutex, RwLock};
+use spin::{Mutex, Once, RwLock};
 
 use crate::arch::cpu::{this_cpu_id, ArchCpu};
-use crate::consts::{INVALID_ADDRESS, PER_CPU_ARRAY_PTR, PER_CPU_SIZE};
+use crate::consts::{
+    CPU_MASK_WORDS, INVALID_ADDRESS, MAX_CPU_NUM, PER_CPU_ARRAY_PTR, PER_CPU_SIZE,
+};
+use crate::event::{run_cross_calls, send_event, IPI_EVENT_SUSPEND};
+use crate::hypercall::SGI_IPI_ID;
 use crate::memory::addr::VirtAddr;
+use crate::stats::CpuStats;
 use crate::zone::Zone;
 use crate::ENTERED_CPUS;
 use core::fmt::Debug;
-use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 // global_asm!(include_str!("./arch/aarch64/page_table.S"),);
 
@@ -20,6 +25,11 @@ pub struct PerCpu {
     pub zone: Option<Arc<RwLock<Zone>>>,
     pub ctrl_lock: Mutex<()>,
     pub boot_cpu: bool,
+    /// Set by `suspend_cpu`, cleared by `resume_cpu`.
+    pub suspend_cpu: AtomicBool,
+    /// Whether this cpu is parked in hvisor with its vcpu context preserved.
+    pub cpu_suspended: AtomicBool,
+    pub stats: CpuStats,
     // percpu stack
 }
 
@@ -36,6 +46,9 @@ impl PerCpu {
                 zone: None,
                 ctrl_lock: Mutex::new(()),
                 boot_cpu: false,
+                suspend_cpu: AtomicBool::new(false),
+                cpu_suspended: AtomicBool::new(false),
+                stats: CpuStats::new(cpu_id),
             })
         };
         #[cfg(target_arch = "riscv64")]
@@ -64,6 +77,38 @@ impl PerCpu {
             self.zone.clone().unwrap().read().gpm.activate();
         }
     }
+
+    /// Spin in hvisor until `resume_cpu` is called on this cpu. Nothing of the
+    /// vcpu context is touched, so the guest continues where it was stopped.
+    pub fn wait_for_resume(&mut self) {
+        let lock = self.ctrl_lock.lock();
+        if self.suspend_cpu.load(Ordering::Acquire) {
+            self.cpu_suspended.store(true, Ordering::Release);
+        }
+        drop(lock);
+        while self.suspend_cpu.load(Ordering::Acquire) {
+            run_cross_calls();
+            core::hint::spin_loop();
+        }
+        self.cpu_suspended.store(false, Ordering::Release);
+    }
+}
+
+static CPU_NUM: Once<usize> = Once::new();
+
+/// Set the number of cpus present. Called by the primary cpu before it wakes
+/// up the others, as it also sizes the per-cpu area.
+pub fn init_cpu_num(cpu_num: usize) {
+    CPU_NUM.call_once(|| cpu_num.clamp(1, MAX_CPU_NUM));
+}
+
+/// Number of cpus hvisor runs on, their ids are `0..cpu_num()`. Only known once
+/// the primary cpu has called `init_cpu_num`.
+pub fn cpu_num() -> usize {
+    match CPU_NUM.get() {
+        Some(&cpu_num) => cpu_num,
+        None => panic!("cpu_num() called before percpu::init_cpu_num()"),
+    }
 }
 
 pub fn get_cpu_data<'a>(cpu_id: usize) -> &'a mut PerCpu {
@@ -80,29 +125,70 @@ pub fn this_zone() -> Arc<RwLock<Zone>> {
     this_cpu_data().zone.clone().unwrap()
 }
 
+/// Park `cpu_id` in hvisor and wait until it has stopped running guest code.
+pub fn suspend_cpu(cpu_id: usize) {
+    let target_data = get_cpu_data(cpu_id);
+    let lock = target_data.ctrl_lock.lock();
+    target_data.suspend_cpu.store(true, Ordering::Release);
+    let target_suspended = target_data.cpu_suspended.load(Ordering::Acquire);
+    drop(lock);
+
+    if !target_suspended {
+        send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SUSPEND);
+        while !target_data.cpu_suspended.load(Ordering::Acquire) {
+            // the target may be suspending this cpu at the same time
+            handle_cpu_requests();
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Do what other cpus may be waiting for while this cpu spins in hvisor: run
+/// their cross-cpu calls, and park this cpu if it's being suspended. Called by
+/// every loop which waits for another cpu, as the SGIs which would do the same
+/// are only taken once this cpu returns to its guest.
+pub fn handle_cpu_requests() {
+    run_cross_calls();
+    let cpu_data = this_cpu_data();
+    if cpu_data.suspend_cpu.load(Ordering::Acquire)
+        && !cpu_data.cpu_suspended.load(Ordering::Acquire)
+    {
+        cpu_data.wait_for_resume();
+    }
+}
+
+/// Let a cpu parked by `suspend_cpu` return to its guest.
+pub fn resume_cpu(cpu_id: usize) {
+    let target_data = get_cpu_data(cpu_id);
+    let _lock = target_data.ctrl_lock.lock();
+    target_data.suspend_cpu.store(false, Ordering::Release);
+}
+
+/// A set of cpus, bit `id % 64` of word `id / 64` stands for cpu `id`.
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct CpuSet {
     pub max_cpu_id: usize,
-    pub bitmap: u64,
+    pub bitmap: [u64; CPU_MASK_WORDS],
 }
 
 impl CpuSet {
-    pub fn new(max_cpu_id: usize, bitmap: u64) -> Self {
+    pub fn new(max_cpu_id: usize, bitmap: [u64; CPU_MASK_WORDS]) -> Self {
+        assert!(max_cpu_id < MAX_CPU_NUM);
         Self { max_cpu_id, bitmap }
     }
     #[allow(unused)]
     pub fn set_bit(&mut self, id: usize) {
         assert!(id <= self.max_cpu_id);
-        self.bitmap |= 1 << id;
+        self.bitmap[id / 64] |= 1 << (id % 64);
     }
     #[allow(unused)]
     pub fn clear_bit(&mut self, id: usize) {
         assert!(id <= self.max_cpu_id);
-        self.bitmap &= !(1 << id);
+        self.bitmap[id / 64] &= !(1 << (id % 64));
     }
     pub fn contains_cpu(&self, id: usize) -> bool {
-        id <= self.max_cpu_id && (self.bitmap & (1 << id)) != 0
+        id <= self.max_cpu_id && (self.bitmap[id / 64] & (1 << (id % 64))) != 0
     }
     #[allow(unused)]
     pub fn first_cpu(&self) -> Option<usize> {
@@ -0,0 +1,191 @@
+//! Discovery of the cpus and the root zone config from the device tree hvisor
+//! is booted with.
+//!
+//! RAM, the GIC, the UART and the CPUs come from the host DTB. Entry, image
+//! addresses and the other devices passed through to the root zone are still
+//! taken from the board's constants.
+#![cfg_attr(feature = "static_root_zone", allow(dead_code, unused_imports))]
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use fdt::{node::FdtNode, Fdt};
+
+use super::{guest_ram_pool, zone_config, ROOT_ZONE_IRQS, ROOT_ZONE_MEMORY_REGIONS};
+use crate::{
+    arch::zone::HvArchZoneConfig,
+    config::{HvConfigMemoryRegion, HvZoneConfig, MEM_TYPE_IO, MEM_TYPE_RAM},
+    consts::{hv_end, hv_start, CPU_MASK_WORDS, MAX_CPU_NUM},
+    error::HvResult,
+    memory::{
+        addr::{align_down, align_up, phys_to_virt, virt_to_phys},
+        PhysAddr,
+    },
+    percpu::{self, CpuSet},
+};
+
+/// `interrupts` cells of the GIC, `<type number flags>`, where type 0 is an SPI.
+const GIC_SPI: u32 = 0;
+const GIC_SPI_BASE: u32 = 32;
+
+fn host_fdt(host_dtb: PhysAddr) -> HvResult<Fdt<'static>> {
+    unsafe { Fdt::from_ptr(phys_to_virt(host_dtb) as *const u8) }.map_err(|e| {
+        hv_err!(
+            EINVAL,
+            format!("invalid host dtb at {:#x}: {:?}", host_dtb, e)
+        )
+    })
+}
+
+/// The MPIDRs (`reg`) of the cpus in `/cpus` which are not disabled.
+pub fn cpu_hw_ids(host_dtb: PhysAddr) -> Option<Vec<u64>> {
+    let fdt = host_fdt(host_dtb).ok()?;
+    let mpidrs: Vec<u64> = fdt
+        .find_node("/cpus")?
+        .children()
+        .filter(|node| node.property("device_type").and_then(|p| p.as_str()) == Some("cpu"))
+        .filter(|node| node.property("status").and_then(|p| p.as_str()) != Some("disabled"))
+        .filter_map(|node| Some(node.reg()?.next()?.starting_address as u64))
+        .collect();
+
+    (!mpidrs.is_empty()).then_some(mpidrs)
+}
+
+#[cfg(not(feature = "static_root_zone"))]
+pub fn root_zone_config(host_dtb: PhysAddr) -> HvResult<HvZoneConfig> {
+    let fdt = host_fdt(host_dtb)?;
+
+    let mut mem_regions: Vec<_> = host_ram(&fdt)
+        .into_iter()
+        .map(|ram| region(MEM_TYPE_RAM, ram))
+        .collect();
+    let mut irqs = Vec::new();
+
+    let gic = fdt
+        .find_compatible(&["arm,gic-v3"])
+        .ok_or(hv_err!(ENODEV, "no GICv3 in host dtb"))?;
+    let arch = gic_config(gic)?;
+
+    if let Some(uart) = stdout(&fdt) {
+        for reg in uart.reg().into_iter().flatten() {
+            let start = reg.starting_address as usize;
+            let size = reg.size.unwrap_or(0);
+            mem_regions.push(region(
+                MEM_TYPE_IO,
+                align_down(start)..align_up(start + size),
+            ));
+        }
+        irqs.extend(spis(uart, gic));
+    } else {
+        warn!("no stdout-path in host dtb, the root zone gets no UART");
+    }
+
+    for board_region in ROOT_ZONE_MEMORY_REGIONS.iter() {
+        let covered = mem_regions.iter().any(|r| {
+            r.physical_start <= board_region.physical_start
+                && board_region.physical_start + board_region.size <= r.physical_start + r.size
+        });
+        if board_region.mem_type != MEM_TYPE_RAM && !covered {
+            mem_regions.push(*board_region);
+        }
+    }
+    for irq in ROOT_ZONE_IRQS {
+        if !irqs.contains(&irq) {
+            irqs.push(irq);
+        }
+    }
+
+    // the root zone starts with all cpus
+    let mut cpus = CpuSet::new(MAX_CPU_NUM - 1, [0; CPU_MASK_WORDS]);
+    (0..percpu::cpu_num()).for_each(|cpu_id| cpus.set_bit(cpu_id));
+    zone_config(cpus.bitmap, &mem_regions, &irqs, arch)
+}
+
+/// All RAM in the host dtb, without hvisor itself and the guest RAM pool.
+fn host_ram(fdt: &Fdt) -> Vec<Range<usize>> {
+    let mut ram: Vec<Range<usize>> = fdt
+        .all_nodes()
+        .filter(|node| node.property("device_type").and_then(|p| p.as_str()) == Some("memory"))
+        .filter_map(|node| node.reg())
+        .flatten()
+        .filter_map(|reg| {
+            let start = reg.starting_address as usize;
+            Some(align_up(start)..align_down(start + reg.size?))
+        })
+        .filter(|ram| !ram.is_empty())
+        .collect();
+
+    let holes = [
+        align_down(virt_to_phys(hv_start()))..align_up(virt_to_phys(hv_end())),
+        guest_ram_pool(),
+    ];
+    for hole in holes {
+        ram = ram
+            .into_iter()
+            .flat_map(|r| [r.start..r.end.min(hole.start), r.start.max(hole.end)..r.end])
+            .filter(|r| !r.is_empty())
+            .collect();
+    }
+    ram
+}
+
+fn gic_config(gic: FdtNode) -> HvResult<HvArchZoneConfig> {
+    let mut reg = gic
+        .reg()
+        .ok_or(hv_err!(EINVAL, "GIC in host dtb has no reg"))?;
+    let (gicd, gicr) = match (reg.next(), reg.next()) {
+        (Some(gicd), Some(gicr)) => (gicd, gicr),
+        _ => return hv_result_err!(EINVAL, "GIC in host dtb has no GICD or GICR"),
+    };
+    let (gicd_size, gicr_size) = match (gicd.size, gicr.size) {
+        (Some(gicd_size), Some(gicr_size)) if gicd_size != 0 && gicr_size != 0 => {
+            (gicd_size, gicr_size)
+        }
+        _ => return hv_result_err!(EINVAL, "GICD or GICR in host dtb has no size"),
+    };
+
+    Ok(HvArchZoneConfig {
+        gicd_base: gicd.starting_address as usize,
+        gicd_size,
+        gicr_base: gicr.starting_address as usize,
+        gicr_size,
+    })
+}
+
+/// The node `/chosen/stdout-path` points to, without the `:<options>` suffix.
+fn stdout<'b, 'a>(fdt: &'b Fdt<'a>) -> Option<FdtNode<'b, 'a>> {
+    let path = fdt
+        .find_node("/chosen")?
+        .property("stdout-path")?
+        .as_str()?;
+    fdt.find_node(path.split(':').next()?)
+}
+
+/// The SPIs of `node`, assuming it is wired to `gic`.
+fn spis(node: FdtNode, gic: FdtNode) -> Vec<u32> {
+    let cells = gic.interrupt_cells().unwrap_or(3);
+    let interrupts = match node.property("interrupts") {
+        Some(interrupts) if cells >= 2 => interrupts.value,
+        _ => return Vec::new(),
+    };
+
+    interrupts
+        .chunks_exact(cells * 4)
+        .map(|int| {
+            let cell = |i: usize| u32::from_be_bytes(int[i * 4..i * 4 + 4].try_into().unwrap());
+            (cell(0), cell(1))
+        })
+        .filter(|&(ty, _)| ty == GIC_SPI)
+        .map(|(_, num)| num + GIC_SPI_BASE)
+        .collect()
+}
+
+fn region(mem_type: u32, range: Range<usize>) -> HvConfigMemoryRegion {
+    HvConfigMemoryRegion {
+        mem_type,
+        flags: 0,
+        physical_start: range.start as _,
+        virtual_start: range.start as _,
+        size: (range.end - range.start) as _,
+    }
+}
@@ -8,18 +8,21 @@ pub const ROOT_ZONE_CPUS: u64 = (1 << 0) | (1 << 1);
 pub const ROOT_ZONE_MEMORY_REGIONS: [HvConfigMemoryRegion; 3] = [
     HvConfigMemoryRegion {
         mem_type: MEM_TYPE_RAM,
+        flags: 0,
         physical_start: 0x50000000,
         virtual_start: 0x50000000,
         size: 0x80000000,
     }, // ram
     HvConfigMemoryRegion {
         mem_type: MEM_TYPE_IO,
+        flags: 0,
         physical_start: 0x30000000,
         virtual_start: 0x30000000,
         size: 0x400000,
     }, // bus@30000000
     HvConfigMemoryRegion {
         mem_type: MEM_TYPE_IO,
+        flags: 0,
         physical_start: 0x30800000,
         virtual_start: 0x30800000,
         size: 0x400000,
@@ -42,3 +45,10 @@ pub const ROOT_ARCH_ZONE_CONFIG: HvArchZoneConfig = HvArchZoneConfig {
     gicr_base: 0x38880000,
     gicr_size: 0xc0000,
 };
+
+/// Physical memory backing `MEM_TYPE_RAM_ALLOC` regions, kept out of the root zone's RAM.
+pub const GUEST_RAM_POOL_START: u64 = 0xd0000000;
+pub const GUEST_RAM_POOL_SIZE: u64 = 0x20000000;
+
+/// SPI injected into the root zone when another zone crashes.
+pub const IRQ_ZONE_FAULT: u32 = 32 + 0x21;
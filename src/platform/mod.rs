@@ -1,8 +1,14 @@
+use alloc::vec::Vec;
+use core::ops::Range;
+
 use crate::{
+    arch::zone::HvArchZoneConfig,
     config::{
         HvConfigMemoryRegion, HvZoneConfig, CONFIG_MAX_INTERRUPTS, CONFIG_MAX_MEMORY_REGIONS,
     },
-    consts::INVALID_ADDRESS,
+    consts::{CPU_MASK_WORDS, INVALID_ADDRESS},
+    error::HvResult,
+    memory::PhysAddr,
 };
 
 #[cfg(all(feature = "platform_qemu", target_arch = "riscv64"))]
@@ -17,39 +23,104 @@ pub mod qemu_aarch64;
 #[cfg(all(feature = "platform_qemu", target_arch = "aarch64"))]
 use qemu_aarch64::*;
 
+#[cfg(all(feature = "platform_qemu", target_arch = "aarch64"))]
+pub use qemu_aarch64::IRQ_ZONE_FAULT;
+
 #[cfg(all(feature = "platform_imx8mp", target_arch = "aarch64"))]
 pub mod imx8mp_aarch64;
 
 #[cfg(all(feature = "platform_imx8mp", target_arch = "aarch64"))]
 use imx8mp_aarch64::*;
 
-pub fn platform_root_zone_config() -> HvZoneConfig {
-    // fill zero for memory regions and interrupts
+#[cfg(all(feature = "platform_imx8mp", target_arch = "aarch64"))]
+pub use imx8mp_aarch64::IRQ_ZONE_FAULT;
 
-    let mut memory_regions = [HvConfigMemoryRegion {
-        mem_type: 0,
-        physical_start: 0,
-        virtual_start: 0,
-        size: 0,
-    }; CONFIG_MAX_MEMORY_REGIONS];
+#[cfg(target_arch = "aarch64")]
+mod dtb;
 
-    memory_regions[..ROOT_ZONE_MEMORY_REGIONS.len()].copy_from_slice(&ROOT_ZONE_MEMORY_REGIONS);
+/// Hardware ids of the cpus present, which are MPIDRs on aarch64 and hart ids
+/// on riscv64. From the host dtb if possible and otherwise from the firmware.
+pub fn cpu_hw_ids(host_dtb: PhysAddr) -> Vec<u64> {
+    #[cfg(target_arch = "aarch64")]
+    if let Some(ids) = dtb::cpu_hw_ids(host_dtb) {
+        return ids;
+    }
+    let _ = host_dtb;
+
+    crate::arch::cpu::firmware_cpu_hw_ids()
+}
+
+/// The root zone config, derived from the host dtb at `host_dtb` unless the
+/// `static_root_zone` feature is enabled. The board's constants are used if the
+/// host dtb can't be used.
+pub fn platform_root_zone_config(host_dtb: PhysAddr) -> HvZoneConfig {
+    #[cfg(all(target_arch = "aarch64", not(feature = "static_root_zone")))]
+    match dtb::root_zone_config(host_dtb) {
+        Ok(config) => return config,
+        Err(e) => warn!("failed to build root zone config from host dtb: {:?}", e),
+    }
+    let _ = host_dtb;
+
+    info!("using the built-in root zone config");
+    // the boards' root zones only have cpus below 64
+    let mut cpus = [0; CPU_MASK_WORDS];
+    cpus[0] = ROOT_ZONE_CPUS;
+    zone_config(
+        cpus,
+        &ROOT_ZONE_MEMORY_REGIONS,
+        &ROOT_ZONE_IRQS,
+        ROOT_ARCH_ZONE_CONFIG,
+    )
+    .unwrap()
+}
+
+/// Build a root zone config with the board's entry and image addresses.
+fn zone_config(
+    cpus: [u64; CPU_MASK_WORDS],
+    mem_regions: &[HvConfigMemoryRegion],
+    irqs: &[u32],
+    arch: HvArchZoneConfig,
+) -> HvResult<HvZoneConfig> {
+    // the root zone has to be able to enable the irq it learns of crashed zones by
+    let mut irqs = irqs.to_vec();
+    if !irqs.contains(&IRQ_ZONE_FAULT) {
+        irqs.push(IRQ_ZONE_FAULT);
+    }
+    if mem_regions.len() > CONFIG_MAX_MEMORY_REGIONS || irqs.len() > CONFIG_MAX_INTERRUPTS {
+        return hv_result_err!(
+            E2BIG,
+            format!(
+                "root zone has {} memory regions and {} irqs",
+                mem_regions.len(),
+                irqs.len()
+            )
+        );
+    }
+
+    // fill zero for memory regions and interrupts
+    let mut memory_regions = [HvConfigMemoryRegion::new_empty(); CONFIG_MAX_MEMORY_REGIONS];
+    memory_regions[..mem_regions.len()].copy_from_slice(mem_regions);
 
     let mut interrupts = [0; CONFIG_MAX_INTERRUPTS];
-    interrupts[..ROOT_ZONE_IRQS.len()].copy_from_slice(&ROOT_ZONE_IRQS);
+    interrupts[..irqs.len()].copy_from_slice(&irqs);
 
-    HvZoneConfig::new(
+    Ok(HvZoneConfig::new(
         0,
-        ROOT_ZONE_CPUS,
-        ROOT_ZONE_MEMORY_REGIONS.len() as u32,
+        cpus,
+        mem_regions.len() as u32,
         memory_regions,
-        ROOT_ZONE_IRQS.len() as u32,
+        irqs.len() as u32,
         interrupts,
         ROOT_ZONE_ENTRY,
         ROOT_ZONE_KERNEL_ADDR,
         INVALID_ADDRESS as _,
         ROOT_ZONE_DTB_ADDR,
         INVALID_ADDRESS as _,
-        ROOT_ARCH_ZONE_CONFIG,
-    )
+        arch,
+    ))
+}
+
+/// The physical range hvisor allocates `MEM_TYPE_RAM_ALLOC` regions from.
+pub fn guest_ram_pool() -> Range<usize> {
+    GUEST_RAM_POOL_START as usize..(GUEST_RAM_POOL_START + GUEST_RAM_POOL_SIZE) as usize
 }
@@ -8,18 +8,21 @@ pub const ROOT_ZONE_CPUS: u64 = (1 << 0) | (1 << 1);
 pub const ROOT_ZONE_MEMORY_REGIONS: [HvConfigMemoryRegion; 3] = [
     HvConfigMemoryRegion {
         mem_type: MEM_TYPE_RAM,
+        flags: 0,
         physical_start: 0x50000000,
         virtual_start: 0x50000000,
-        size: 0x80000000,
+        size: 0x60000000,
     }, // ram
     HvConfigMemoryRegion {
         mem_type: MEM_TYPE_IO,
+        flags: 0,
         physical_start: 0x9000000,
         virtual_start: 0x9000000,
         size: 0x1000,
     }, // serial
     HvConfigMemoryRegion {
         mem_type: MEM_TYPE_IO,
+        flags: 0,
         physical_start: 0xa000000,
         virtual_start: 0xa000000,
         size: 0x4000,
@@ -34,3 +37,11 @@ pub const ROOT_ARCH_ZONE_CONFIG: HvArchZoneConfig = HvArchZoneConfig {
     gicr_base: 0x80a0000,
     gicr_size: 0xf60000,
 };
+
+/// Physical memory backing `MEM_TYPE_RAM_ALLOC` regions, kept out of the root zone's RAM.
+pub const GUEST_RAM_POOL_START: u64 = 0xb0000000;
+pub const GUEST_RAM_POOL_SIZE: u64 = 0x10000000;
+
+/// SPI injected into the root zone when another zone crashes. SPIs 10-15 are
+/// unused by the virt machine, its virtio-mmio devices start at SPI 16.
+pub const IRQ_ZONE_FAULT: u32 = 32 + 15;
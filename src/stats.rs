@@ -0,0 +1,194 @@
+//! Per-cpu counters of the exits from the guests.
+//!
+//! Every exit is counted by class, MMIO accesses by region and interrupts by
+//! id. The time from entering hvisor to returning to the guest is added to the
+//! counter of the exit, in ticks of the arch counter. The root zone reads the
+//! counters of a cpu with `HvCpuStats`. Building with `STATS=on` also dumps
+//! them to the console every `STATS_DUMP_PERIOD_SECS`.
+
+use core::fmt;
+
+use crate::arch::cpu::{counter_frequency, read_counter};
+
+pub const HV_CPU_STATS_VERSION: u32 = 1;
+/// Interrupt ids which are counted one by one, the GIC has at most 1020.
+pub const STATS_IRQS: usize = 1024;
+/// Event ids which are counted one by one, see `event.rs`.
+pub const STATS_EVENTS: usize = 16;
+/// MMIO regions which are counted one by one, accesses to further regions go
+/// to `mmio_other`.
+pub const STATS_MMIO_REGIONS: usize = 32;
+
+const STATS_DUMP_PERIOD_SECS: u64 = 10;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HvExitCounter {
+    pub count: u64,
+    /// Ticks of the arch counter spent in hvisor for these exits.
+    pub ticks: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HvMmioCounter {
+    /// Guest physical start of the region, 0 for an unused entry.
+    pub base: u64,
+    pub counter: HvExitCounter,
+}
+
+/// The counters of one cpu, as copied to the root zone.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct HvCpuStats {
+    pub version: u32,
+    pub cpu_id: u32,
+    /// Frequency of the arch counter, to convert `ticks`.
+    pub counter_freq: u64,
+    pub hvc: HvExitCounter,
+    pub smc: HvExitCounter,
+    pub sysreg: HvExitCounter,
+    /// Aborts on guest addresses without RAM or an MMIO handler.
+    pub unbacked_abort: HvExitCounter,
+    pub mmio: [HvMmioCounter; STATS_MMIO_REGIONS],
+    pub mmio_other: HvExitCounter,
+    pub irq: [HvExitCounter; STATS_IRQS],
+    pub event: [HvExitCounter; STATS_EVENTS],
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExitClass {
+    Hvc,
+    Smc,
+    Sysreg,
+    UnbackedAbort,
+    /// An access to the MMIO region starting at the address.
+    Mmio(usize),
+    Irq(usize),
+    Event(usize),
+}
+
+pub struct CpuStats {
+    pub counters: HvCpuStats,
+    /// Arch counter when the current exit entered hvisor.
+    exit_start: u64,
+    /// What the current exit was, its time goes to the last class recorded.
+    exit_class: Option<ExitClass>,
+    /// Arch counter of the next dump, `u64::MAX` without `STATS=on`.
+    next_dump: u64,
+}
+
+impl CpuStats {
+    pub fn new(cpu_id: usize) -> Self {
+        let counter_freq = counter_frequency();
+        Self {
+            counters: HvCpuStats {
+                version: HV_CPU_STATS_VERSION,
+                cpu_id: cpu_id as _,
+                counter_freq,
+                hvc: HvExitCounter::default(),
+                smc: HvExitCounter::default(),
+                sysreg: HvExitCounter::default(),
+                unbacked_abort: HvExitCounter::default(),
+                mmio: [HvMmioCounter::default(); STATS_MMIO_REGIONS],
+                mmio_other: HvExitCounter::default(),
+                irq: [HvExitCounter::default(); STATS_IRQS],
+                event: [HvExitCounter::default(); STATS_EVENTS],
+            },
+            exit_start: 0,
+            exit_class: None,
+            next_dump: match option_env!("STATS") {
+                Some("on") => read_counter() + STATS_DUMP_PERIOD_SECS * counter_freq,
+                _ => u64::MAX,
+            },
+        }
+    }
+
+    /// The counter of `class`, none for ids which are not counted.
+    fn counter(&mut self, class: ExitClass) -> Option<&mut HvExitCounter> {
+        let counters = &mut self.counters;
+        match class {
+            ExitClass::Hvc => Some(&mut counters.hvc),
+            ExitClass::Smc => Some(&mut counters.smc),
+            ExitClass::Sysreg => Some(&mut counters.sysreg),
+            ExitClass::UnbackedAbort => Some(&mut counters.unbacked_abort),
+            ExitClass::Mmio(base) => {
+                // the entry of the region, or the first unused one
+                match counters
+                    .mmio
+                    .iter()
+                    .position(|mmio| mmio.base == base as u64 || mmio.base == 0)
+                {
+                    Some(i) => {
+                        counters.mmio[i].base = base as _;
+                        Some(&mut counters.mmio[i].counter)
+                    }
+                    None => Some(&mut counters.mmio_other),
+                }
+            }
+            ExitClass::Irq(irq_id) => counters.irq.get_mut(irq_id),
+            ExitClass::Event(event_id) => counters.event.get_mut(event_id),
+        }
+    }
+
+    /// Called when a guest exit enters hvisor.
+    pub fn exit_enter(&mut self) {
+        self.exit_start = read_counter();
+        self.exit_class = None;
+    }
+
+    /// Count one `class`. The time of the current exit goes to the last class
+    /// recorded, e.g. to the event rather than to the SGI which delivered it.
+    pub fn record(&mut self, class: ExitClass) {
+        if let Some(counter) = self.counter(class) {
+            counter.count += 1;
+            self.exit_class = Some(class);
+        }
+    }
+
+    /// Called right before returning to the guest.
+    pub fn exit_leave(&mut self) {
+        let now = read_counter();
+        if let Some(class) = self.exit_class.take() {
+            let ticks = now.wrapping_sub(self.exit_start);
+            if let Some(counter) = self.counter(class) {
+                counter.ticks += ticks;
+            }
+        }
+        if now >= self.next_dump {
+            self.dump();
+            self.next_dump = now + STATS_DUMP_PERIOD_SECS * self.counters.counter_freq;
+        }
+    }
+
+    /// Print the counters which are not 0.
+    pub fn dump(&self) {
+        let c = &self.counters;
+        let line = |name: fmt::Arguments, counter: &HvExitCounter| {
+            if counter.count != 0 {
+                println!(
+                    "  {:<24} count {:<10} avg ticks {}",
+                    name,
+                    counter.count,
+                    counter.ticks / counter.count
+                );
+            }
+        };
+
+        println!("exit stats of cpu {}:", c.cpu_id);
+        line(format_args!("hvc"), &c.hvc);
+        line(format_args!("smc"), &c.smc);
+        line(format_args!("sysreg"), &c.sysreg);
+        line(format_args!("unbacked abort"), &c.unbacked_abort);
+        for mmio in c.mmio.iter().filter(|mmio| mmio.base != 0) {
+            line(format_args!("mmio {:#x}", mmio.base), &mmio.counter);
+        }
+        line(format_args!("mmio other"), &c.mmio_other);
+        for (irq_id, irq) in c.irq.iter().enumerate() {
+            line(format_args!("irq {}", irq_id), irq);
+        }
+        for (event_id, event) in c.event.iter().enumerate() {
+            line(format_args!("event {}", event_id), event);
+        }
+    }
+}
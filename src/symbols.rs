@@ -0,0 +1,81 @@
+//! Symbol table of hvisor itself, for symbolizing backtraces.
+//!
+//! `HV_SYMBOLS` is a placeholder of fixed size in `.rodata`. After linking,
+//! `scripts/gen_symbols.py` fills it in the ELF with the function symbols, so
+//! that no address changes. The layout is a [`SymbolTableHeader`], followed by
+//! `count` [`SymbolEntry`]s sorted by address, followed by the names.
+
+use core::{mem::size_of, slice, str};
+
+/// Size reserved for the symbol table, must match `scripts/gen_symbols.py`.
+const SYMBOL_TABLE_SIZE: usize = 0x40000;
+/// `HVSY`, set once the table is filled.
+const SYMBOL_TABLE_MAGIC: u32 = 0x5953_5648;
+/// Left in the placeholder, also keeps it out of `.bss`.
+const SYMBOL_TABLE_EMPTY: u32 = 0xffff_ffff;
+
+#[repr(C)]
+struct SymbolTableHeader {
+    magic: u32,
+    count: u32,
+}
+
+#[repr(C)]
+struct SymbolEntry {
+    addr: u64,
+    name_offset: u32,
+    name_len: u32,
+}
+
+#[repr(C, align(8))]
+struct SymbolTable {
+    header: SymbolTableHeader,
+    data: [u8; SYMBOL_TABLE_SIZE - size_of::<SymbolTableHeader>()],
+}
+
+#[no_mangle]
+#[used]
+static HV_SYMBOLS: SymbolTable = SymbolTable {
+    header: SymbolTableHeader {
+        magic: SYMBOL_TABLE_EMPTY,
+        count: 0,
+    },
+    data: [0; SYMBOL_TABLE_SIZE - size_of::<SymbolTableHeader>()],
+};
+
+/// The table as patched in the image. The compiler knows the initial value of
+/// `HV_SYMBOLS`, so it must not see where the bytes come from.
+fn symbol_table() -> &'static SymbolTable {
+    unsafe { &*core::hint::black_box(&HV_SYMBOLS as *const SymbolTable) }
+}
+
+/// The function containing `addr` and the offset of `addr` in it.
+pub fn lookup(addr: usize) -> Option<(&'static str, usize)> {
+    let table = symbol_table();
+    if table.header.magic != SYMBOL_TABLE_MAGIC {
+        return None;
+    }
+    let count = table.header.count as usize;
+    if count * size_of::<SymbolEntry>() > table.data.len() {
+        return None;
+    }
+    let entries =
+        unsafe { slice::from_raw_parts(table.data.as_ptr() as *const SymbolEntry, count) };
+
+    // the last symbol at or below `addr`
+    let idx = entries.partition_point(|entry| entry.addr as usize <= addr);
+    let entry = entries.get(idx.checked_sub(1)?)?;
+    let names = &table.data[count * size_of::<SymbolEntry>()..];
+    let name = names
+        .get(entry.name_offset as usize..)?
+        .get(..entry.name_len as usize)?;
+    Some((
+        str::from_utf8(name).unwrap_or("<invalid symbol>"),
+        addr - entry.addr as usize,
+    ))
+}
+
+/// Whether the table has been filled in by `scripts/gen_symbols.py`.
+pub fn available() -> bool {
+    symbol_table().header.magic == SYMBOL_TABLE_MAGIC
+}
@@ -0,0 +1,166 @@
+//! Per-cpu trace buffers of hypervisor events.
+//!
+//! Tracing through the console changes the timing too much to chase races, so
+//! every cpu records its events into a ring buffer in memory instead. The
+//! buffers are mapped into the root zone at their physical address, which
+//! `HvTraceBuffer` returns for a cpu, so the root zone drains them itself.
+//!
+//! A buffer is `TRACE_BUFFER_SIZE` bytes, a [`HvTraceHeader`] at offset 0
+//! followed by `num_entries` [`HvTraceEntry`]s at offset `TRACE_HEADER_SIZE`,
+//! all little endian. Entry `i` is at index `i % num_entries`. The cpu of the
+//! buffer is the only writer of the entries, `head` and `dropped`, the root
+//! zone is the only writer of `tail`:
+//!
+//! - hvisor writes entry `head` if `head - tail < num_entries`, then
+//!   increments `head` with release semantics. Otherwise the entry is dropped
+//!   and `dropped` incremented.
+//! - the root zone reads `head` with acquire semantics, consumes the entries
+//!   `tail..head`, then stores the new `tail` with release semantics.
+//!
+//! Timestamps are ticks of the arch counter at `counter_freq`.
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Once;
+
+use crate::{
+    arch::{
+        cpu::{counter_frequency, read_counter, this_cpu_id},
+        s2pt::Stage2PageTable,
+    },
+    consts::PAGE_SIZE,
+    error::HvResult,
+    memory::{Frame, GuestPhysAddr, MemFlags, MemoryRegion, MemorySet},
+};
+
+pub const TRACE_BUFFER_SIZE: usize = 0x10000;
+pub const TRACE_HEADER_SIZE: usize = 64;
+/// `HVTR`
+pub const TRACE_MAGIC: u32 = 0x5254_5648;
+pub const TRACE_VERSION: u32 = 1;
+
+/// A guest exit, `args`: exit reason, syndrome (ESR_EL2 on aarch64).
+pub const TRACE_EXIT: u32 = 1;
+/// A virtual interrupt injected, `args`: irq id, whether it's a hardware irq.
+pub const TRACE_IRQ_INJECT: u32 = 2;
+/// A hypercall, `args`: code, arg0.
+pub const TRACE_HYPERCALL: u32 = 3;
+/// A PSCI call, `args`: function id, arg0.
+pub const TRACE_PSCI: u32 = 4;
+/// A zone created, `args`: zone id.
+pub const TRACE_ZONE_CREATE: u32 = 5;
+/// A zone changed its state, `args`: zone id, new `ZoneState`.
+pub const TRACE_ZONE_STATE: u32 = 6;
+/// A zone removed, `args`: zone id.
+pub const TRACE_ZONE_REMOVE: u32 = 7;
+/// A virtio request pushed to the virtio bridge, `args`: address, is write.
+pub const TRACE_VIRTIO_REQ: u32 = 8;
+/// A virtio result taken from the virtio bridge, `args`: target zone, irq id.
+pub const TRACE_VIRTIO_RES: u32 = 9;
+
+#[repr(C)]
+pub struct HvTraceHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub cpu_id: u32,
+    pub entry_size: u32,
+    pub num_entries: u32,
+    pub reserved: u32,
+    pub counter_freq: u64,
+    /// Number of entries ever written.
+    pub head: AtomicU64,
+    /// Number of entries consumed, written by the root zone.
+    pub tail: AtomicU64,
+    /// Number of entries lost because the buffer was full.
+    pub dropped: AtomicU64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvTraceEntry {
+    pub timestamp: u64,
+    /// One of the `TRACE_*` kinds.
+    pub kind: u32,
+    pub reserved: u32,
+    pub args: [u64; 2],
+}
+
+const NUM_ENTRIES: usize = (TRACE_BUFFER_SIZE - TRACE_HEADER_SIZE) / size_of::<HvTraceEntry>();
+
+/// The buffer of every cpu, indexed by cpu id.
+static TRACE_BUFFERS: Once<Vec<Frame>> = Once::new();
+
+pub fn init(cpu_num: usize) {
+    assert!(size_of::<HvTraceHeader>() <= TRACE_HEADER_SIZE);
+    TRACE_BUFFERS.call_once(|| {
+        (0..cpu_num)
+            .map(|cpu_id| {
+                let mut frame = Frame::new_contiguous(TRACE_BUFFER_SIZE / PAGE_SIZE, 0)
+                    .expect("no memory for trace buffers");
+                frame.clear();
+                let header = unsafe { &mut *(frame.as_mut_ptr() as *mut HvTraceHeader) };
+                header.magic = TRACE_MAGIC;
+                header.version = TRACE_VERSION;
+                header.cpu_id = cpu_id as _;
+                header.entry_size = size_of::<HvTraceEntry>() as _;
+                header.num_entries = NUM_ENTRIES as _;
+                header.counter_freq = counter_frequency();
+                frame
+            })
+            .collect()
+    });
+}
+
+/// Map the trace buffers of all cpus into `gpm` of the root zone, read-write
+/// at their physical address.
+pub fn map_buffers(gpm: &mut MemorySet<Stage2PageTable>) -> HvResult {
+    for frame in TRACE_BUFFERS.get().unwrap() {
+        gpm.insert(MemoryRegion::new_with_offset_mapper(
+            frame.start_paddr() as GuestPhysAddr,
+            frame.start_paddr(),
+            frame.size(),
+            MemFlags::READ | MemFlags::WRITE,
+        ))?;
+    }
+    Ok(())
+}
+
+/// The physical address of the trace buffer of `cpu_id`.
+pub fn buffer_paddr(cpu_id: usize) -> Option<usize> {
+    Some(TRACE_BUFFERS.get()?.get(cpu_id)?.start_paddr())
+}
+
+/// Record an event of `kind` into the buffer of this cpu. Events before the
+/// buffers are set up are not recorded.
+pub fn trace(kind: u32, arg0: u64, arg1: u64) {
+    let frame = match TRACE_BUFFERS
+        .get()
+        .and_then(|frames| frames.get(this_cpu_id()))
+    {
+        Some(frame) => frame,
+        None => return,
+    };
+    let header = unsafe { &*(frame.as_ptr() as *const HvTraceHeader) };
+    let head = header.head.load(Ordering::Relaxed);
+    // the root zone may have written any `tail`
+    if head.wrapping_sub(header.tail.load(Ordering::Acquire)) >= NUM_ENTRIES as u64 {
+        header.dropped.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let entry = HvTraceEntry {
+        timestamp: read_counter(),
+        kind,
+        reserved: 0,
+        args: [arg0, arg1],
+    };
+    unsafe {
+        let entries = frame.as_ptr().add(TRACE_HEADER_SIZE) as *mut HvTraceEntry;
+        entries
+            .add(head as usize % NUM_ENTRIES)
+            .write_volatile(entry);
+    }
+    header.head.store(head + 1, Ordering::Release);
+}
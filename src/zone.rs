@@ -1,54 +1,192 @@
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use psci::error::INVALID_ADDRESS;
-use spin::RwLock;
+use numeric_enum_macro::numeric_enum;
+use spin::{Mutex, MutexGuard, RwLock};
 
-use crate::arch::mm::new_s2_memory_set;
-use crate::arch::s2pt::Stage2PageTable;
-use crate::config::HvZoneConfig;
-use crate::consts::MAX_CPU_NUM;
+use crate::arch::mm::{dcache_clean_range, new_s2_memory_set};
+use crate::arch::paging::PagingInstr;
+use crate::arch::s2pt::{S2PTInstr, Stage2PageTable};
+use crate::arch::zone::HvArchZoneFault;
+use crate::config::{
+    HvConfigMemoryRegion, HvZoneConfig, CONFIG_MAX_MEMORY_REGIONS, MEM_FLAG_NO_SCRUB, MEM_TYPE_RAM,
+    MEM_TYPE_RAM_ALLOC,
+};
+use crate::consts::{hv_end, hv_start, CPU_MASK_WORDS, MAX_CPU_NUM};
 
+use crate::arch::cpu::this_cpu_id;
 use crate::error::HvResult;
-use crate::memory::addr::GuestPhysAddr;
+use crate::event::{
+    call_on_cpus, send_event, IPI_EVENT_MEM_HOTPLUG, IPI_EVENT_SHUTDOWN, IPI_EVENT_WAKEUP,
+    IPI_EVENT_ZONE_FAULT,
+};
+use crate::hypercall::SGI_IPI_ID;
+use crate::memory::addr::{is_aligned, phys_to_virt, virt_to_phys, GuestPhysAddr, PhysAddr};
+use crate::memory::ram_pool::RamRegion;
+use crate::memory::vmid::Vmid;
 use crate::memory::{MMIOConfig, MMIOHandler, MMIORegion, MemorySet};
-use crate::percpu::{get_cpu_data, this_zone, CpuSet};
-use core::panic;
+use crate::percpu::{
+    cpu_num, get_cpu_data, handle_cpu_requests, resume_cpu, suspend_cpu, this_cpu_data, this_zone,
+    CpuSet,
+};
+use crate::platform::{guest_ram_pool, IRQ_ZONE_FAULT};
+use crate::trace::{trace, TRACE_ZONE_CREATE, TRACE_ZONE_REMOVE, TRACE_ZONE_STATE};
+use core::mem::size_of;
+use core::{panic, slice};
+
+numeric_enum! {
+    #[repr(u32)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum ZoneState {
+        /// The zone is created but its boot cpu has not been woken up yet.
+        Created = 0,
+        Running = 1,
+        /// All cpus of the zone are parked in hvisor by `HvZonePause`.
+        Paused = 2,
+        /// A cpu of the zone took a fault hvisor can't handle, all cpus of the zone
+        /// are stopped until it is restarted or shut down.
+        Crashed = 3,
+        /// The zone is being restarted by `zone_restart`.
+        Restarting = 4,
+    }
+}
 
 pub struct Zone {
     pub id: usize,
+    pub state: ZoneState,
     pub mmio: Vec<MMIOConfig>,
     pub cpu_set: CpuSet,
     pub irq_bitmap: [u32; 1024 / 32],
     pub gpm: MemorySet<Stage2PageTable>,
+    /// The config this zone is created from.
+    pub config: HvZoneConfig,
+    /// RAM allocated by hvisor for this zone, freed together with the zone.
+    pub ram_regions: Vec<RamRegion>,
+    /// The last fault that crashed the zone.
+    pub fault: Option<HvArchZoneFault>,
+    /// Tags the stage 2 TLB entries of `gpm`, freed after it.
+    pub vmid: Vmid,
+    /// `seq` of the last `HvMemHotplugEvent` sent to the zone.
+    pub mem_hotplug_seq: u64,
+    /// Number of times the zone was restarted.
+    pub restarts: u64,
+    /// Guest physical address the kernel of the zone is loaded at, if known.
+    pub kernel_ipa: Option<usize>,
+    /// Guest physical address of the dtb the boot cpu is started with, if known.
+    pub dtb_ipa: Option<usize>,
 }
 
 impl Zone {
-    pub fn new(zoneid: usize) -> Self {
-        Self {
-            id: zoneid,
-            gpm: new_s2_memory_set(),
-            cpu_set: CpuSet::new(MAX_CPU_NUM as usize, 0),
+    pub fn new(config: &HvZoneConfig) -> HvResult<Self> {
+        let vmid = Vmid::new()?;
+        let mut gpm = new_s2_memory_set();
+        gpm.set_vmid(vmid.id());
+        Ok(Self {
+            id: config.zone_id as _,
+            state: ZoneState::Created,
+            gpm,
+            cpu_set: CpuSet::new(MAX_CPU_NUM - 1, [0; CPU_MASK_WORDS]),
             mmio: Vec::new(),
             irq_bitmap: [0; 1024 / 32],
+            config: config.clone(),
+            ram_regions: Vec::new(),
+            fault: None,
+            vmid,
+            mem_hotplug_seq: 0,
+            restarts: 0,
+            kernel_ipa: None,
+            dtb_ipa: None,
+        })
+    }
+
+    pub fn set_state(&mut self, state: ZoneState) {
+        trace(TRACE_ZONE_STATE, self.id as _, state as _);
+        self.state = state;
+    }
+
+    /// Back the `MEM_TYPE_RAM_ALLOC` regions of the config with zeroed memory from the
+    /// guest RAM pool. They are turned into plain RAM regions at the allocated address.
+    pub fn ram_alloc_init(&mut self) -> HvResult {
+        for region in self.config.memory_regions_mut() {
+            if region.mem_type != MEM_TYPE_RAM_ALLOC {
+                continue;
+            }
+            self.ram_regions.push(alloc_ram(self.id, region)?);
         }
+        Ok(())
     }
 
-    // pub fn suspend(&self) {
-    //     trace!("suspending cpu_set = {:#x?}", self.cpu_set);
-    //     self.cpu_set.iter_except(this_cpu_id()).for_each(|cpu_id| {
-    //         trace!("try to suspend cpu_id = {:#x?}", cpu_id);
-    //         suspend_cpu(cpu_id);
-    //     });
-    //     info!("send sgi done!");
-    // }
+    /// Zero the RAM of this zone, except the regions with `MEM_FLAG_NO_SCRUB`, so
+    /// that whoever gets it next can't read what the zone left behind. The cpus of
+    /// the zone must have left it.
+    pub fn scrub_ram(&self) {
+        for region in self.config.memory_regions() {
+            self.scrub_region(region);
+        }
+    }
 
-    // pub fn resume(&self) {
-    //     trace!("resuming cpu_set = {:#x?}", self.cpu_set);
-    //     self.cpu_set.iter_except(this_cpu_id()).for_each(|cpu_id| {
-    //         trace!("try to resume cpu_id = {:#x?}", cpu_id);
-    //         resume_cpu(cpu_id);
-    //     });
-    // }
+    /// Zero `region` as `scrub_ram` does, if it's RAM without `MEM_FLAG_NO_SCRUB`.
+    fn scrub_region(&self, region: &HvConfigMemoryRegion) {
+        if region.mem_type != MEM_TYPE_RAM || region.flags & MEM_FLAG_NO_SCRUB != 0 {
+            return;
+        }
+        info!(
+            "zone {}: scrubbing ram {:#x?}",
+            self.id,
+            region.physical_start..region.physical_start + region.size
+        );
+        let start = phys_to_virt(region.physical_start as _);
+        unsafe { core::ptr::write_bytes(start as *mut u8, 0, region.size as _) };
+        // the next guest may read it with the caches off
+        dcache_clean_range(start, region.size as _);
+    }
+
+    /// Tell the guest that `region` was added or removed by `op`: write a
+    /// `HvMemHotplugEvent` to `mem_hotplug_ipa` and send `mem_hotplug_irq` to
+    /// the boot cpu. Nothing is sent if the config has no such interrupt.
+    fn notify_mem_hotplug(&mut self, op: u32, region: &HvConfigMemoryRegion) {
+        let irq = self.config.mem_hotplug_irq;
+        if irq == 0 || !self.config.interrupts().contains(&irq) {
+            return;
+        }
+        self.mem_hotplug_seq += 1;
+        let event = HvMemHotplugEvent {
+            seq: self.mem_hotplug_seq,
+            op,
+            reserved: 0,
+            ipa: region.virtual_start,
+            size: region.size,
+        };
+        let buf = unsafe {
+            slice::from_raw_parts(
+                &event as *const HvMemHotplugEvent as *const u8,
+                size_of::<HvMemHotplugEvent>(),
+            )
+        };
+        if let Err(e) = self
+            .gpm
+            .copy_to_guest(self.config.mem_hotplug_ipa as _, buf)
+        {
+            warn!(
+                "zone {}: failed to write the memory hotplug event: {:?}",
+                self.id, e
+            );
+            return;
+        }
+        let boot_cpu = self.cpu_set.first_cpu().unwrap();
+        send_event(boot_cpu, SGI_IPI_ID as _, IPI_EVENT_MEM_HOTPLUG);
+    }
+
+    /// Rewrite the cpus in the dtb of the zone to its vCPUs, before its boot cpu
+    /// starts. The guest only finds the vCPUs there.
+    pub fn fixup_guest_dtb(&self) -> HvResult {
+        if !self.has_vcpu_ids() {
+            return Ok(());
+        }
+        match self.dtb_ipa {
+            Some(dtb_ipa) => self.fixup_guest_dtb_cpus(dtb_ipa),
+            None => hv_result_err!(EINVAL, format!("zone {} has no dtb", self.id)),
+        }
+    }
 
     // pub fn owns_cpu(&self, id: usize) -> bool {
     //     self.cpu_set.contains_cpu(id)
@@ -106,6 +244,117 @@ impl Zone {
         let bit_pos = (irq_id % 32) as usize;
         (self.irq_bitmap[idx] & (1 << bit_pos)) != 0
     }
+
+    /// Whether the zone sees its cpus as vCPUs numbered from 0, in the order of
+    /// the cpus in its config. The root zone boots with the host dtb and keeps
+    /// the physical cpu ids.
+    pub fn has_vcpu_ids(&self) -> bool {
+        self.id != 0
+    }
+
+    /// The number of vCPUs the guest of the zone sees.
+    pub fn num_vcpus(&self) -> usize {
+        if self.has_vcpu_ids() {
+            self.config.cpus().iter().count()
+        } else {
+            cpu_num()
+        }
+    }
+
+    /// The physical cpu of vCPU `vcpu_id` of the zone.
+    pub fn vcpu_to_cpu(&self, vcpu_id: usize) -> Option<usize> {
+        if self.has_vcpu_ids() {
+            self.config.cpus().iter().nth(vcpu_id)
+        } else {
+            (vcpu_id < cpu_num()).then_some(vcpu_id)
+        }
+    }
+
+    /// The vCPU id of physical cpu `cpu_id` in the zone.
+    pub fn cpu_to_vcpu(&self, cpu_id: usize) -> Option<usize> {
+        if self.has_vcpu_ids() {
+            self.config.cpus().iter().position(|cpu| cpu == cpu_id)
+        } else {
+            (cpu_id < cpu_num()).then_some(cpu_id)
+        }
+    }
+    /// Snapshot of this zone reported to the root zone by `HvZoneList`.
+    pub fn info(&self) -> HvZoneInfo {
+        let regions = self.config.memory_regions();
+        let mut memory_regions = [HvConfigMemoryRegion::new_empty(); CONFIG_MAX_MEMORY_REGIONS];
+        memory_regions[..regions.len()].copy_from_slice(regions);
+        HvZoneInfo {
+            zone_id: self.id as _,
+            state: self.state.into(),
+            cpus: self.cpu_set.bitmap,
+            num_memory_regions: regions.len() as _,
+            memory_regions,
+            irq_bitmap: self.irq_bitmap,
+        }
+    }
+}
+
+/// Version of the layout written by `HvZoneList`. Bump it whenever
+/// [`HvZoneListHeader`] or [`HvZoneInfo`] changes.
+pub const HV_ZONE_INFO_VERSION: u32 = 2;
+
+/// Header at the beginning of the buffer filled by `HvZoneList`, followed by
+/// `num_entries` [`HvZoneInfo`]s of `entry_size` bytes each.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvZoneListHeader {
+    pub version: u32,
+    /// Number of zones in hvisor, may be larger than `num_entries`.
+    pub num_zones: u32,
+    /// Number of entries written into the buffer.
+    pub num_entries: u32,
+    pub entry_size: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvZoneInfo {
+    pub zone_id: u32,
+    /// See [`ZoneState`].
+    pub state: u32,
+    /// Same layout as the `cpus` of [`HvZoneConfig`].
+    pub cpus: [u64; CPU_MASK_WORDS],
+    pub num_memory_regions: u32,
+    pub memory_regions: [HvConfigMemoryRegion; CONFIG_MAX_MEMORY_REGIONS],
+    pub irq_bitmap: [u32; 1024 / 32],
+}
+
+/// The buffer `HvDirtyLogFetch` writes the dirty log of a memory region to.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvDirtyLogBuffer {
+    /// Guest physical start of the region in the zone.
+    pub ipa: u64,
+    /// Address of the bitmap in the root zone. Bit `i % 64` of the little
+    /// endian word `i / 64` is set if page `i` of the region was written.
+    pub bitmap_addr: u64,
+    /// Size of the bitmap in bytes, at least one bit per page rounded up to words.
+    pub bitmap_size: u64,
+}
+
+/// RAM was added to the zone.
+pub const MEM_HOTPLUG_ADD: u32 = 1;
+/// RAM was removed from the zone.
+pub const MEM_HOTPLUG_REMOVE: u32 = 2;
+
+/// Written to `mem_hotplug_ipa` of a zone when RAM is added to or removed from
+/// it at runtime, before `mem_hotplug_irq` is injected. Removed RAM is already
+/// gone by then, the root zone has to get the guest to offline it first.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvMemHotplugEvent {
+    /// Incremented with every event, so the guest can tell it missed one.
+    pub seq: u64,
+    /// `MEM_HOTPLUG_ADD` or `MEM_HOTPLUG_REMOVE`.
+    pub op: u32,
+    pub reserved: u32,
+    pub ipa: u64,
+    pub size: u64,
 }
 
 static ZONE_LIST: RwLock<Vec<Arc<RwLock<Zone>>>> = RwLock::new(vec![]);
@@ -132,7 +381,211 @@ pub fn remove_zone(zone_id: usize) {
         .find(|(_, zone)| zone.read().id == zone_id)
         .unwrap();
     let removed_zone = zone_list.remove(idx);
+    trace(TRACE_ZONE_REMOVE, zone_id as _, 0);
     assert_eq!(Arc::strong_count(&removed_zone), 1);
+
+    if idx != 0 {
+        let root = zone_list[0].clone();
+        remap_to_root_zone(&root, &removed_zone.read());
+    }
+}
+
+/// Invalidate the stage 2 TLB entries of `vmid` on `cpus`, after mappings were
+/// removed from or changed in the `gpm` at `root_paddr` the zone may be
+/// running on. Inserting
+/// into an empty range needs none, as no entry of it can be cached. The zone
+/// must not be locked by the caller: the cpus may be spinning on its lock,
+/// and they only run the flush once they get it.
+pub fn tlb_shootdown(cpus: impl Iterator<Item = usize>, root_paddr: PhysAddr, vmid: usize) {
+    call_on_cpus(
+        cpus,
+        |root_paddr, vmid| S2PTInstr::flush(None, Some(root_paddr), vmid),
+        (root_paddr, vmid),
+    );
+}
+
+/// Unmap the RAM and IO of `mem_regions` from the root zone. None of its cpus
+/// can reach them anymore when this returns.
+fn unmap_ram_from_root_zone(
+    root: &Arc<RwLock<Zone>>,
+    mem_regions: &[HvConfigMemoryRegion],
+) -> HvResult {
+    suspend_zone(root);
+    let mut root_w = root.write();
+    let root_cpus = root_w.cpu_set;
+    let vmid = root_w.vmid.id();
+    let root_paddr = root_w.gpm.root_paddr();
+    let res = root_w.pt_unmap_zone_regions(mem_regions);
+    drop(root_w);
+    // the flush of `delete` is local to this cpu on some arches, the others are
+    // parked and need their own
+    tlb_shootdown(root_cpus.iter_except(this_cpu_id()), root_paddr, vmid);
+    resume_zone(root);
+    res
+}
+
+/// Take the RAM and IO regions, the irqs and the cpus of `zone` away from the
+/// root zone. The other cpus of the root zone are suspended meanwhile, so they
+/// don't run into a region while it is being split.
+fn unmap_from_root_zone(root: &Arc<RwLock<Zone>>, zone: &Zone) -> HvResult {
+    unmap_ram_from_root_zone(root, zone.config.memory_regions())?;
+    let mut root_w = root.write();
+    for &irq in zone.config.interrupts() {
+        root_w.remove_irq_from_bitmap(irq);
+    }
+    zone.cpu_set.iter().for_each(|cpu_id| {
+        root_w.cpu_set.clear_bit(cpu_id);
+    });
+    Ok(())
+}
+
+/// Give back to the root zone what `unmap_from_root_zone` took from it. Only
+/// the resources listed in the config of the root zone are returned.
+fn remap_to_root_zone(root: &Arc<RwLock<Zone>>, zone: &Zone) {
+    let mut root_w = root.write();
+    if let Err(e) = root_w.pt_remap_zone_regions(zone.config.memory_regions()) {
+        error!(
+            "failed to give the memory of zone {} back: {:?}",
+            zone.id, e
+        );
+    }
+    for &irq in zone.config.interrupts() {
+        if root_w.config.interrupts().contains(&irq) {
+            root_w.insert_irq_to_bitmap(irq);
+        }
+    }
+    let root_cpus = root_w.config.cpus();
+    zone.cpu_set.iter().for_each(|cpu_id| {
+        if root_cpus.contains_cpu(cpu_id) {
+            root_w.cpu_set.set_bit(cpu_id);
+            let cpu_data = get_cpu_data(cpu_id);
+            let _lock = cpu_data.ctrl_lock.lock();
+            cpu_data.zone = Some(root.clone());
+        }
+    });
+}
+
+/// Park all cpus of the zone except the current one in hvisor. The zone must
+/// not be locked by the caller, its cpus may be waiting for the lock before
+/// they can be parked.
+pub fn suspend_zone(zone: &Arc<RwLock<Zone>>) {
+    let (zone_id, cpu_set) = {
+        let zone_r = zone.read();
+        (zone_r.id, zone_r.cpu_set)
+    };
+    trace!("suspending cpu_set = {:#x?}", cpu_set);
+    cpu_set.iter_except(this_cpu_id()).for_each(|cpu_id| {
+        trace!("try to suspend cpu_id = {:#x?}", cpu_id);
+        suspend_cpu(cpu_id);
+    });
+    info!("zone {} suspended", zone_id);
+}
+
+/// Let the cpus parked by `suspend_zone` continue from their saved context.
+pub fn resume_zone(zone: &Arc<RwLock<Zone>>) {
+    let (zone_id, cpu_set) = {
+        let zone_r = zone.read();
+        (zone_r.id, zone_r.cpu_set)
+    };
+    trace!("resuming cpu_set = {:#x?}", cpu_set);
+    cpu_set.iter_except(this_cpu_id()).for_each(|cpu_id| {
+        trace!("try to resume cpu_id = {:#x?}", cpu_id);
+        resume_cpu(cpu_id);
+    });
+    info!("zone {} resumed", zone_id);
+}
+
+/// Held while zones are created, destroyed or changed, which suspends cpus of
+/// the root zone or of the zone. Two cpus suspending each other would wait
+/// forever.
+static ZONE_MANAGEMENT: Mutex<()> = Mutex::new(());
+
+/// Lock `ZONE_MANAGEMENT`. The cpu holding it may be suspending this one.
+pub fn lock_zone_management() -> MutexGuard<'static, ()> {
+    lock_zone_management_while(|| true).unwrap()
+}
+
+/// Like `lock_zone_management`, but gives up once `keep_trying` returns false.
+pub fn lock_zone_management_while(
+    keep_trying: impl Fn() -> bool,
+) -> Option<MutexGuard<'static, ()>> {
+    loop {
+        if let Some(lock) = ZONE_MANAGEMENT.try_lock() {
+            return Some(lock);
+        }
+        if !keep_trying() {
+            return None;
+        }
+        handle_cpu_requests();
+        core::hint::spin_loop();
+    }
+}
+
+/// Reboot the zone in place: the boot cpu starts over from `config.entry_point`
+/// and the other cpus go back to wait for PSCI CPU_ON. The memory set and the
+/// MMIO handlers are kept. Only a running, paused or crashed zone can be
+/// restarted, the zone is `Restarting` meanwhile. The caller must hold the
+/// zone management lock but not the zone, and the current cpu is left to it.
+pub fn zone_restart(zone: &Arc<RwLock<Zone>>) -> HvResult {
+    let (zone_id, old_state) = {
+        let mut zone_w = zone.write();
+        let state = zone_w.state;
+        match state {
+            ZoneState::Running | ZoneState::Crashed | ZoneState::Paused => {}
+            ZoneState::Restarting => {
+                return hv_result_err!(EBUSY, format!("zone {} is restarting", zone_w.id))
+            }
+            _ => {
+                return hv_result_err!(
+                    EINVAL,
+                    format!("zone {} can't be restarted in its state", zone_w.id)
+                )
+            }
+        }
+        zone_w.set_state(ZoneState::Restarting);
+        (zone_w.id, state)
+    };
+    let paused = old_state == ZoneState::Paused;
+    info!("restarting zone {}", zone_id);
+    // stop the guest first so that it can't touch the GIC during the reset
+    if !paused {
+        suspend_zone(zone);
+    }
+
+    let mut zone_w = zone.write();
+    // the root zone may have loaded a new dtb
+    if let Err(e) = zone_w.fixup_guest_dtb() {
+        zone_w.set_state(old_state);
+        drop(zone_w);
+        if !paused {
+            resume_zone(zone);
+        }
+        return Err(e);
+    }
+    zone_w.arch_irqchip_reset();
+    let boot_cpu = zone_w.cpu_set.first_cpu().unwrap();
+    zone_w
+        .cpu_set
+        .iter_except(this_cpu_id())
+        .for_each(|cpu_id| {
+            let cpu_data = get_cpu_data(cpu_id);
+            let _lock = cpu_data.ctrl_lock.lock();
+            if cpu_id == boot_cpu {
+                cpu_data.cpu_on_entry = zone_w.config.entry_point as _;
+                send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_WAKEUP);
+            } else {
+                cpu_data.cpu_on_entry = crate::consts::INVALID_ADDRESS;
+                send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
+            }
+        });
+    zone_w.fault = None;
+    zone_w.restarts += 1;
+    zone_w.set_state(ZoneState::Running);
+    drop(zone_w);
+
+    // the events are handled once the cpus leave the suspend loop
+    resume_zone(zone);
+    Ok(())
 }
 
 pub fn find_zone(zone_id: usize) -> Option<Arc<RwLock<Zone>>> {
@@ -147,6 +600,15 @@ pub fn this_zone_id() -> usize {
     this_zone().read().id
 }
 
+/// Collect the info of every zone in ZONE_LIST.
+pub fn zone_list_info() -> Vec<HvZoneInfo> {
+    ZONE_LIST
+        .read()
+        .iter()
+        .map(|zone| zone.read().info())
+        .collect()
+}
+
 // #[repr(C)]
 // #[derive(Debug, Clone)]
 // pub struct ZoneConfig {
@@ -160,7 +622,156 @@ pub fn this_zone_id() -> usize {
 //     pub dtb_load_paddr: u64,
 // }
 
-pub fn zone_create(config: &HvZoneConfig) -> HvResult<Arc<RwLock<Zone>>> {
+/// Make sure the cpus, RAM and interrupts of `config` are not already taken by
+/// another zone or by hvisor itself. The RAM and interrupts of the root zone
+/// are not considered, as the other zones are carved out of them.
+fn check_zone_resources(config: &HvZoneConfig) -> HvResult {
+    for cpu_id in config.cpus().iter() {
+        if cpu_id >= cpu_num() {
+            return hv_result_err!(EINVAL, format!("cpu {} does not exist", cpu_id));
+        }
+        let cpu_data = get_cpu_data(cpu_id);
+        if let Some(zone) = &cpu_data.zone {
+            // an offline cpu of the root zone can be taken
+            let is_root = ZONE_LIST
+                .read()
+                .first()
+                .is_some_and(|root| Arc::ptr_eq(zone, root));
+            if !is_root || cpu_data.arch_cpu.psci_on {
+                return hv_result_err!(
+                    EBUSY,
+                    format!("cpu {} is owned by zone {}", cpu_id, zone.read().id)
+                );
+            }
+        }
+    }
+
+    let zone_list = ZONE_LIST.read();
+    for region in config.memory_regions() {
+        if region.mem_type == MEM_TYPE_RAM {
+            check_ram_region(region, &zone_list)?;
+        }
+    }
+
+    // only the root zone, which is created first, may have it
+    if !zone_list.is_empty() && config.interrupts().contains(&IRQ_ZONE_FAULT) {
+        return hv_result_err!(
+            EBUSY,
+            format!(
+                "irq {} reports zone faults to the root zone",
+                IRQ_ZONE_FAULT
+            )
+        );
+    }
+    for &irq in config.interrupts() {
+        for zone in zone_list.iter().skip(1) {
+            let zone = zone.read();
+            if zone.config.interrupts().contains(&irq) {
+                return hv_result_err!(EBUSY, format!("irq {} is owned by zone {}", irq, zone.id));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Make sure the RAM `region` is neither hvisor memory, nor the guest RAM pool,
+/// nor RAM of any zone but the root zone.
+fn check_ram_region(region: &HvConfigMemoryRegion, zone_list: &[Arc<RwLock<Zone>>]) -> HvResult {
+    let overlaps = |a: &HvConfigMemoryRegion, start: u64, end: u64| {
+        a.physical_start < end && start < a.physical_start + a.size
+    };
+    let hv_start = virt_to_phys(hv_start()) as u64;
+    let hv_end = virt_to_phys(hv_end()) as u64;
+    let pool = guest_ram_pool();
+    if overlaps(region, hv_start, hv_end) {
+        return hv_result_err!(
+            EINVAL,
+            format!(
+                "ram {:#x}..{:#x} overlaps hvisor memory {:#x}..{:#x}",
+                region.physical_start,
+                region.physical_start + region.size,
+                hv_start,
+                hv_end
+            )
+        );
+    }
+    // not even the root zone, it would share the memory with the zones the pool backs
+    if overlaps(region, pool.start as _, pool.end as _) {
+        return hv_result_err!(
+            EINVAL,
+            format!(
+                "ram {:#x}..{:#x} overlaps guest ram pool {:#x?}",
+                region.physical_start,
+                region.physical_start + region.size,
+                pool
+            )
+        );
+    }
+    for zone in zone_list.iter().skip(1) {
+        let zone = zone.read();
+        let conflict = zone.config.memory_regions().iter().find(|other| {
+            other.mem_type == MEM_TYPE_RAM
+                && overlaps(
+                    region,
+                    other.physical_start,
+                    other.physical_start + other.size,
+                )
+        });
+        if let Some(other) = conflict {
+            return hv_result_err!(
+                EBUSY,
+                format!(
+                    "ram {:#x}..{:#x} overlaps ram {:#x}..{:#x} of zone {}",
+                    region.physical_start,
+                    region.physical_start + region.size,
+                    other.physical_start,
+                    other.physical_start + other.size,
+                    zone.id
+                )
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Stop the zone of the current cpu after `fault`, which hvisor can't handle, and
+/// tell the root zone. The current cpu is parked. A fault of the root zone
+/// can't be reported to anyone, so it brings down the whole system.
+pub fn this_zone_crash(fault: HvArchZoneFault) -> ! {
+    let cpu_data = this_cpu_data();
+    let zone = this_zone();
+    if is_this_root_zone() {
+        panic!("root zone crashed: {:#x?}", fault);
+    }
+
+    let mut zone_w = zone.write();
+    error!("zone {} crashed: {:#x?}", zone_w.id, fault);
+    // another cpu of the zone may have crashed at the same time
+    if zone_w.state != ZoneState::Crashed {
+        zone_w.set_state(ZoneState::Crashed);
+        zone_w.fault = Some(fault);
+        zone_w.cpu_set.iter_except(cpu_data.id).for_each(|cpu_id| {
+            let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
+            get_cpu_data(cpu_id).cpu_on_entry = crate::consts::INVALID_ADDRESS;
+            send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
+        });
+
+        let root_cpu = root_zone().read().cpu_set.first_cpu().unwrap();
+        send_event(root_cpu, SGI_IPI_ID as _, IPI_EVENT_ZONE_FAULT);
+    }
+    drop(zone_w);
+    drop(zone);
+
+    cpu_data.arch_cpu.idle();
+}
+
+/// Create the zone of `config`, its kernel and dtb are loaded at the guest physical
+/// addresses `kernel_ipa` and `dtb_ipa`.
+pub fn zone_create(
+    config: &HvZoneConfig,
+    kernel_ipa: Option<usize>,
+    dtb_ipa: Option<usize>,
+) -> HvResult<Arc<RwLock<Zone>>> {
     // we create the new zone here
     // TODO: create Zone with cpu_set
     let zone_id = config.zone_id as usize;
@@ -168,34 +779,27 @@ pub fn zone_create(config: &HvZoneConfig) -> HvResult<Arc<RwLock<Zone>>> {
     if find_zone(zone_id).is_some() {
         return hv_result_err!(EEXIST);
     }
+    check_zone_resources(config)?;
 
-    let mut zone = Zone::new(zone_id);
-    zone.pt_init(config.memory_regions()).unwrap();
+    let mut zone = Zone::new(config)?;
+    zone.ram_alloc_init()?;
+    let zone_config = zone.config;
+    zone.pt_init(zone_config.memory_regions()).unwrap();
     zone.mmio_init(&config.arch);
     zone.irq_bitmap_init(config.interrupts());
 
-    config.cpus().iter().for_each(|cpu_id| {
-        zone.cpu_set.set_bit(*cpu_id as _);
-    });
+    zone.cpu_set = config.cpus();
+    zone.kernel_ipa = kernel_ipa;
+    zone.dtb_ipa = dtb_ipa;
 
-    // pub struct HvConfigMemoryRegion {
-    //     pub mem_type: u32,
-    //     pub physical_start: u64,
-    //     pub virtual_start: u64,
-    //     pub size: u64,
-    // }
-    let mut dtb_ipa = INVALID_ADDRESS as u64;
-    for region in config.memory_regions() {
-        // region contains config.dtb_load_paddr?
-        if region.physical_start <= config.dtb_load_paddr
-            && region.physical_start + region.size > config.dtb_load_paddr
-        {
-            dtb_ipa = region.virtual_start + config.dtb_load_paddr - region.physical_start;
-        }
-    }
-    info!("zone cpu_set: {:#b}", zone.cpu_set.bitmap);
+    info!("zone cpu_set: {:x?}", zone.cpu_set.bitmap);
     let cpu_set = zone.cpu_set;
 
+    if let Some(root) = ZONE_LIST.read().first().cloned() {
+        unmap_from_root_zone(&root, &zone)?;
+        zone.arch_irqchip_reset();
+    }
+
     let new_zone_pointer = Arc::new(RwLock::new(zone));
     {
         cpu_set.iter().for_each(|cpuid| {
@@ -206,10 +810,220 @@ pub fn zone_create(config: &HvZoneConfig) -> HvResult<Arc<RwLock<Zone>>> {
                 cpu_data.boot_cpu = true;
             }
             cpu_data.cpu_on_entry = config.entry_point as _;
-            cpu_data.dtb_ipa = dtb_ipa as _;
+            cpu_data.dtb_ipa = dtb_ipa.unwrap_or(crate::consts::INVALID_ADDRESS);
         });
     }
     add_zone(new_zone_pointer.clone());
+    trace(TRACE_ZONE_CREATE, zone_id as _, 0);
 
     Ok(new_zone_pointer)
 }
+
+/// Undo `zone_create` after the zone failed to start. None of its cpus may have
+/// been woken up. The RAM of the zone is scrubbed and, like the rest of its
+/// resources, given back to the root zone or the RAM pool.
+pub fn zone_discard(zone: Arc<RwLock<Zone>>) {
+    let zone_r = zone.read();
+    let zone_id = zone_r.id;
+    warn!("discarding zone {}", zone_id);
+    zone_r.cpu_set.iter().for_each(|cpu_id| {
+        let cpu_data = get_cpu_data(cpu_id);
+        let _lock = cpu_data.ctrl_lock.lock();
+        cpu_data.zone = None;
+        cpu_data.boot_cpu = false;
+        cpu_data.cpu_on_entry = crate::consts::INVALID_ADDRESS;
+    });
+    zone_r.scrub_ram();
+    drop(zone_r);
+    drop(zone);
+    // panics if a reference to the zone is left behind
+    remove_zone(zone_id);
+}
+
+/// Back the `MEM_TYPE_RAM_ALLOC` `region` of zone `zone_id` with zeroed memory
+/// from the guest RAM pool, and turn it into a plain RAM region at the
+/// allocated address.
+fn alloc_ram(zone_id: usize, region: &mut HvConfigMemoryRegion) -> HvResult<RamRegion> {
+    // 2M aligned, so that the region can be mapped with block descriptors
+    let ram = RamRegion::new_zero(region.size as _, 0x200000)?;
+    info!(
+        "zone {}: allocated ram {:#x?} for ipa {:#x}",
+        zone_id,
+        ram.start_paddr()..ram.start_paddr() + ram.size(),
+        region.virtual_start
+    );
+    region.mem_type = MEM_TYPE_RAM;
+    region.physical_start = ram.start_paddr() as _;
+    region.size = ram.size() as _;
+    Ok(ram)
+}
+
+/// Add the RAM `region` to a zone which may be running. `MEM_TYPE_RAM` is taken
+/// from the root zone like the RAM of a new zone, `MEM_TYPE_RAM_ALLOC` comes
+/// from the guest RAM pool. The guest is told with a `MEM_HOTPLUG_ADD` event.
+pub fn zone_add_memory(zone: &Arc<RwLock<Zone>>, mut region: HvConfigMemoryRegion) -> HvResult {
+    let (ipa, size) = (region.virtual_start as usize, region.size as usize);
+    if size == 0 || !is_aligned(ipa) || !is_aligned(size) {
+        return hv_result_err!(
+            EINVAL,
+            format!("ram {:#x}+{:#x} is not page aligned", ipa, size)
+        );
+    }
+    {
+        let zone_r = zone.read();
+        if zone_r.config.memory_regions().len() == CONFIG_MAX_MEMORY_REGIONS {
+            return hv_result_err!(
+                E2BIG,
+                format!("zone {} has no memory region left", zone_r.id)
+            );
+        }
+        let mmio = zone_r.mmio.iter().find(|mmio| {
+            mmio.region.start < ipa + size && ipa < mmio.region.start + mmio.region.size
+        });
+        if let Some(mmio) = mmio {
+            return hv_result_err!(
+                EBUSY,
+                format!(
+                    "ram {:#x}+{:#x} overlaps mmio region {:#x?}",
+                    ipa, size, mmio.region
+                )
+            );
+        }
+    }
+
+    let zone_id = zone.read().id;
+    let mut ram = None;
+    let root = root_zone();
+    match region.mem_type {
+        MEM_TYPE_RAM => {
+            if !is_aligned(region.physical_start as _) {
+                return hv_result_err!(
+                    EINVAL,
+                    format!("ram at {:#x} is not page aligned", region.physical_start)
+                );
+            }
+            check_ram_region(&region, &ZONE_LIST.read())?;
+            unmap_ram_from_root_zone(&root, slice::from_ref(&region))?;
+        }
+        MEM_TYPE_RAM_ALLOC => ram = Some(alloc_ram(zone_id, &mut region)?),
+        _ => {
+            return hv_result_err!(
+                EINVAL,
+                format!("memory type {} can't be added", region.mem_type)
+            )
+        }
+    }
+
+    let mut zone_w = zone.write();
+    // e.g. the IPA range is already mapped
+    if let Err(e) = zone_w.pt_map_region(&region) {
+        drop(zone_w);
+        if ram.is_none() {
+            root.write()
+                .pt_remap_zone_regions(slice::from_ref(&region))?;
+        }
+        return Err(e);
+    }
+    zone_w.config.push_memory_region(region)?;
+    zone_w.ram_regions.extend(ram);
+    info!("zone {}: added ram {:#x?}", zone_id, region);
+    zone_w.notify_mem_hotplug(MEM_HOTPLUG_ADD, &region);
+    Ok(())
+}
+
+/// Remove the RAM region at `ipa` of `size` bytes, from the config or added by
+/// `zone_add_memory`, from a zone which may be running. It's scrubbed like the
+/// RAM of a zone which is shut down, then goes back to the root zone or to the
+/// guest RAM pool. The guest is told with a `MEM_HOTPLUG_REMOVE` event.
+pub fn zone_remove_memory(zone: &Arc<RwLock<Zone>>, ipa: u64, size: u64) -> HvResult {
+    let mut zone_w = zone.write();
+    let index = zone_w
+        .config
+        .memory_regions()
+        .iter()
+        .position(|region| {
+            region.mem_type == MEM_TYPE_RAM && region.virtual_start == ipa && region.size == size
+        })
+        .ok_or(hv_err!(
+            ENOENT,
+            format!("zone {} has no ram {:#x}+{:#x}", zone_w.id, ipa, size)
+        ))?;
+    zone_w.gpm.unmap_partial(ipa as _, size as _)?;
+    let region = zone_w.config.remove_memory_region(index);
+    let ram = zone_w
+        .ram_regions
+        .iter()
+        .position(|ram| ram.start_paddr() == region.physical_start as usize)
+        .map(|i| zone_w.ram_regions.remove(i));
+    let cpus = zone_w.cpu_set;
+    let vmid = zone_w.vmid.id();
+    let root_paddr = zone_w.gpm.root_paddr();
+    drop(zone_w);
+
+    // no cpu of the zone may reach the memory anymore once it's handed out again
+    tlb_shootdown(cpus.iter_except(this_cpu_id()), root_paddr, vmid);
+    let mut zone_w = zone.write();
+    zone_w.scrub_region(&region);
+    info!("zone {}: removed ram {:#x?}", zone_w.id, region);
+    zone_w.notify_mem_hotplug(MEM_HOTPLUG_REMOVE, &region);
+    drop(zone_w);
+    match ram {
+        Some(ram) => drop(ram),
+        None => root_zone()
+            .write()
+            .pt_remap_zone_regions(slice::from_ref(&region))?,
+    }
+    Ok(())
+}
+
+/// Change the mappings of the zone with `f` while its cpus are parked, as pages
+/// may be unmapped for a moment.
+fn zone_remap(
+    zone: &Arc<RwLock<Zone>>,
+    f: impl FnOnce(&mut MemorySet<Stage2PageTable>) -> HvResult,
+) -> HvResult {
+    let paused = zone.read().state == ZoneState::Paused;
+    if !paused {
+        suspend_zone(zone);
+    }
+    let mut zone_w = zone.write();
+    let res = f(&mut zone_w.gpm);
+    let cpus = zone_w.cpu_set;
+    let vmid = zone_w.vmid.id();
+    let root_paddr = zone_w.gpm.root_paddr();
+    drop(zone_w);
+    tlb_shootdown(cpus.iter_except(this_cpu_id()), root_paddr, vmid);
+    if !paused {
+        resume_zone(zone);
+    }
+    res
+}
+
+/// Start logging the pages the zone writes to in its memory region at `ipa`,
+/// see `MemorySet::start_dirty_log`.
+pub fn zone_start_dirty_log(zone: &Arc<RwLock<Zone>>, ipa: usize) -> HvResult {
+    zone_remap(zone, |gpm| gpm.start_dirty_log(ipa))
+}
+
+/// Stop the dirty log of the memory region at `ipa` of the zone.
+pub fn zone_stop_dirty_log(zone: &Arc<RwLock<Zone>>, ipa: usize) -> HvResult {
+    zone_remap(zone, |gpm| gpm.stop_dirty_log(ipa))
+}
+
+/// Take and clear the dirty log of the memory region at `ipa` of the zone, see
+/// `MemorySet::fetch_dirty_log`. The pages not marked in it are unchanged
+/// since the last fetch when this returns.
+pub fn zone_fetch_dirty_log(
+    zone: &Arc<RwLock<Zone>>,
+    ipa: usize,
+    max_words: usize,
+) -> HvResult<Vec<u64>> {
+    let mut zone_w = zone.write();
+    let bitmap = zone_w.gpm.fetch_dirty_log(ipa, max_words)?;
+    let cpus = zone_w.cpu_set;
+    let vmid = zone_w.vmid.id();
+    let root_paddr = zone_w.gpm.root_paddr();
+    drop(zone_w);
+    tlb_shootdown(cpus.iter_except(this_cpu_id()), root_paddr, vmid);
+    Ok(bitmap)
+}